@@ -4,14 +4,13 @@ main:
     HALT
 
 print:
-    LDA [B]
+    LDAB [B+]
     AND A
-    JZ end
+    JZ .end
     OUT
-    INC B
     JMP print
-    end:
+    .end:
     RET
 
 data:
-    .ascii "Hello, World!\n\0"
\ No newline at end of file
+    .asciiz "Hello, World!\n"
\ No newline at end of file
@@ -0,0 +1,2369 @@
+use crate::condition;
+use crate::debug_info::{LineEntry, LineTable};
+use crate::flag;
+use crate::isa::Instruction;
+use crate::register::GeneralPurposeRegister;
+use expression::Expr;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+mod expression;
+
+/// Address of the first byte of the assembled output, unless [`AssemblerOptions::origin`] says otherwise.
+pub const START_ADDRESS: u16 = 0x0000;
+
+/// Section assembled into until the first `.section`.
+const DEFAULT_SECTION: &str = ".text";
+
+/// Symbol holding the address of the start of the current line, written `$` or `*` in source.
+const HERE: &str = "$";
+
+/// Mnemonic prefixes that take a condition, either as a suffix (`JNZ`) or as the first operand (`JMPIF NZ, label`).
+const CONDITIONAL: &[(&str, &str)] = &[
+    ("JR", "JRIF"),
+    ("JX", "JXIF"),
+    ("J", "JMPIF"),
+    ("CALL", "CALLIF"),
+    ("RET", "RETIF"),
+];
+
+/// Bytes shown on each row of a listing from [`compile_listing`].
+const LISTING_BYTES_PER_ROW: usize = 4;
+
+/// How deeply macro invocations may nest before expansion gives up.
+const MAX_MACRO_DEPTH: usize = 64;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CompileError {
+    /// The mnemonic does not exist, or none of its forms take the given operands.
+    InvalidInstruction(String),
+    UnknownDirective(String),
+    InvalidOperand(String),
+    InvalidCondition(String),
+    InvalidFlag(String),
+    InvalidString(String),
+    UndefinedSymbol(String),
+    /// `.def` of a symbol that already exists, or `.equ` of one with a different value. Use `.redef` or `.set` to
+    /// change it.
+    Redefinition(String),
+    /// The value of a symbol does not fit the operand it is used in.
+    OutOfRange(String, u16),
+    /// The target of a short relative branch is too far away. Holds the distance from the end of the instruction.
+    BranchOutOfRange(String, i32),
+    /// A file given to `.incbin` could not be read. Holds the path and the reason.
+    IncludeFailed(String, String),
+    DivisionByZero,
+    /// A `.macro` without a matching `.endmacro`.
+    UnterminatedMacro(String),
+    /// Macro invocations nested more than [`MAX_MACRO_DEPTH`] deep, usually because a macro invokes itself.
+    MacroRecursion(String),
+    /// A `.rept` without a matching `.endr`.
+    UnterminatedRepeat,
+    /// A symbol named after a register, which an operand would always read as the register.
+    ReservedWord(String),
+    /// A label defined a second time. Holds the line of the first definition.
+    DuplicateLabel(String, usize),
+    /// A label with the name of a symbol already defined by `.def` or `.equ`.
+    LabelRedefinition(String),
+    /// Output written over bytes already written, usually after `.org`. Holds the first address written twice and
+    /// the line that wrote it first.
+    Overlap(u16, usize),
+    /// Data in an uninitialized section such as `.bss`, which can only reserve space.
+    InitializedData(String),
+    /// Output at an address before the start of the image. Holds the address and the start.
+    BelowOrigin(u16, u16),
+}
+
+impl CompileError {
+    /// The piece of source the error is about, if it names one.
+    pub fn token(&self) -> Option<&str> {
+        match self {
+            CompileError::InvalidInstruction(token)
+            | CompileError::UnknownDirective(token)
+            | CompileError::InvalidOperand(token)
+            | CompileError::InvalidCondition(token)
+            | CompileError::InvalidFlag(token)
+            | CompileError::InvalidString(token)
+            | CompileError::UndefinedSymbol(token)
+            | CompileError::Redefinition(token)
+            | CompileError::OutOfRange(token, _)
+            | CompileError::BranchOutOfRange(token, _)
+            | CompileError::IncludeFailed(token, _)
+            | CompileError::UnterminatedMacro(token)
+            | CompileError::MacroRecursion(token)
+            | CompileError::ReservedWord(token)
+            | CompileError::InitializedData(token)
+            | CompileError::DuplicateLabel(token, _)
+            | CompileError::LabelRedefinition(token) => Some(token),
+            CompileError::DivisionByZero
+            | CompileError::UnterminatedRepeat
+            | CompileError::Overlap(..)
+            | CompileError::BelowOrigin(..) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::InvalidInstruction(line) => write!(f, "invalid instruction `{line}`"),
+            CompileError::UnknownDirective(directive) => {
+                write!(f, "unknown directive `{directive}`")
+            }
+            CompileError::InvalidOperand(operand) => write!(f, "invalid operand `{operand}`"),
+            CompileError::InvalidCondition(condition) => {
+                write!(f, "invalid condition `{condition}`")
+            }
+            CompileError::InvalidFlag(flag) => write!(f, "invalid flag `{flag}`"),
+            CompileError::InvalidString(string) => write!(f, "invalid string {string}"),
+            CompileError::UndefinedSymbol(symbol) => write!(f, "undefined symbol `{symbol}`"),
+            CompileError::Redefinition(symbol) => {
+                write!(f, "`{symbol}` is already defined; use .redef to change it")
+            }
+            CompileError::OutOfRange(text, value) => {
+                write!(
+                    f,
+                    "`{text}` is {value} (${value:04X}), which does not fit the operand"
+                )
+            }
+            CompileError::BranchOutOfRange(text, distance) => {
+                write!(
+                    f,
+                    "`{text}` is {distance} bytes away, out of range of a short branch (-128 to 127)"
+                )
+            }
+            CompileError::IncludeFailed(path, reason) => {
+                write!(f, "cannot include \"{path}\": {reason}")
+            }
+            CompileError::DivisionByZero => write!(f, "division by zero"),
+            CompileError::UnterminatedMacro(name) => write!(f, "macro `{name}` has no .endmacro"),
+            CompileError::MacroRecursion(name) => {
+                write!(f, "macro `{name}` nested more than {MAX_MACRO_DEPTH} deep")
+            }
+            CompileError::UnterminatedRepeat => write!(f, ".rept has no .endr"),
+            CompileError::ReservedWord(name) => {
+                write!(f, "`{name}` is a register and cannot be a symbol")
+            }
+            CompileError::DuplicateLabel(label, line) => {
+                write!(f, "label `{label}` is already defined on line {line}")
+            }
+            CompileError::LabelRedefinition(label) => {
+                write!(f, "label `{label}` is already defined by .def or .equ")
+            }
+            CompileError::Overlap(address, line) => {
+                write!(
+                    f,
+                    "output at ${address:04X} overlaps output from line {line}"
+                )
+            }
+            CompileError::BelowOrigin(address, origin) => {
+                write!(
+                    f,
+                    "output at ${address:04X} is before the start of the image at ${origin:04X}"
+                )
+            }
+            CompileError::InitializedData(section) => {
+                write!(
+                    f,
+                    "section `{section}` is uninitialized and can only reserve space"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Something that assembles but is probably a mistake.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CompileWarning {
+    /// A symbolic value from `$FF80` to `$FFFF` in a byte operand, which keeps only its low byte. Negating the
+    /// expression (`-x`) marks the value as intentionally negative.
+    Truncated(String, u16),
+    /// `.org` to an address below the current one. Holds the current address and the new one.
+    OrgBackwards(u16, u16),
+    /// A label that is never referred to.
+    UnusedLabel(String),
+}
+
+impl CompileWarning {
+    /// The piece of source the warning is about, if it names one.
+    pub fn token(&self) -> Option<&str> {
+        match self {
+            CompileWarning::Truncated(token, _) | CompileWarning::UnusedLabel(token) => Some(token),
+            CompileWarning::OrgBackwards(..) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CompileWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileWarning::Truncated(text, value) => write!(
+                f,
+                "warning: `{text}` is ${value:04X}, truncated to the byte ${:02X}",
+                *value as u8
+            ),
+            CompileWarning::OrgBackwards(from, to) => {
+                write!(f, "warning: .org moves back from ${from:04X} to ${to:04X}")
+            }
+            CompileWarning::UnusedLabel(label) => {
+                write!(f, "warning: label `{label}` is never used")
+            }
+        }
+    }
+}
+
+/// An error or warning with where in the source it occurred.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic<T = CompileError> {
+    /// Line number, starting from 1. `None` if the diagnostic is not about a particular line.
+    pub line: Option<usize>,
+    /// Column of the offending token on the line, starting from 1, if it could be found there. Tokens from a macro
+    /// expansion are not found on the invocation line.
+    pub column: Option<usize>,
+    pub kind: T,
+}
+
+pub type Warning = Diagnostic<CompileWarning>;
+
+/// Symbol names with their values.
+pub type Symbols = Vec<(String, u16)>;
+
+impl<T: std::fmt::Display> std::fmt::Display for Diagnostic<T> {
+    /// `line:column: message`, leaving out whichever of the line and column is unknown.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "{line}:")?;
+            if let Some(column) = self.column {
+                write!(f, "{column}:")?;
+            }
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// An error with the line number it occurred on, if any, before its column is looked up.
+type LineError = (Option<usize>, CompileError);
+
+/// Settings that change how source is read and where it is placed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AssemblerOptions {
+    /// Treat symbols that differ only in case as the same symbol. Mnemonics, directives, registers, conditions, flags
+    /// and macro names are case-insensitive regardless.
+    pub ignore_case: bool,
+    /// Address of the first byte of the image, where `.text` starts. Output below it is an error.
+    pub origin: u16,
+    /// Symbols defined before the first line, as if by `.def`.
+    pub defines: Vec<(String, u16)>,
+    /// Characters that start a comment as well as `;`, such as `#`.
+    pub comment_chars: Vec<char>,
+    /// Take an identifier at the very start of a line as a label even without a `:`. Instructions and directives
+    /// must then be indented. Local labels still need the `:`, as they look like directives.
+    pub labels_without_colons: bool,
+}
+
+impl Default for AssemblerOptions {
+    fn default() -> Self {
+        Self {
+            ignore_case: false,
+            origin: START_ADDRESS,
+            defines: Vec::new(),
+            comment_chars: Vec::new(),
+            labels_without_colons: false,
+        }
+    }
+}
+
+/// How a symbol's value is written into the output once it is known.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Relocation {
+    /// A single byte, holding either an unsigned or a sign-extended value.
+    Absolute8,
+    /// A little-endian word.
+    Absolute16,
+    /// A little-endian word holding the value relative to the end of the instruction.
+    Relative16,
+    /// A signed byte holding the value relative to the end of the instruction.
+    Relative8,
+}
+
+impl Relocation {
+    /// Number of bytes patched.
+    fn size(self) -> usize {
+        match self {
+            Relocation::Absolute8 | Relocation::Relative8 => 1,
+            Relocation::Absolute16 | Relocation::Relative16 => 2,
+        }
+    }
+
+    /// Write `value` into `output` at `position`. `origin` is the address just after the instruction, which relative
+    /// values are measured from, and `text` is the operand, for errors.
+    fn patch(
+        self,
+        output: &mut [u8],
+        position: usize,
+        origin: u16,
+        value: u16,
+        text: &str,
+    ) -> Result<(), CompileError> {
+        match self {
+            Relocation::Absolute8 => {
+                if (0x0100..0xFF80).contains(&value) {
+                    return Err(CompileError::OutOfRange(text.to_string(), value));
+                }
+                output[position] = value as u8;
+            }
+            Relocation::Absolute16 => {
+                output[position..position + 2].copy_from_slice(&value.to_le_bytes());
+            }
+            Relocation::Relative16 => {
+                let offset = value.wrapping_sub(origin);
+                output[position..position + 2].copy_from_slice(&offset.to_le_bytes());
+            }
+            Relocation::Relative8 => {
+                output[position] = short_offset(text, origin, value)? as u8;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The signed byte offset from `origin` to `target`, for a short branch written as `text`.
+fn short_offset(text: &str, origin: u16, target: u16) -> Result<i8, CompileError> {
+    let distance = target.wrapping_sub(origin) as i16 as i32;
+    i8::try_from(distance).map_err(|_| CompileError::BranchOutOfRange(text.to_string(), distance))
+}
+
+/// The source text of an operand that refers to symbols, with how it is to be patched in.
+type Fixup<'a> = Option<(&'a str, Relocation)>;
+
+/// A reference to symbols to be patched once every label is known.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Erratum<'a> {
+    /// Index into the output of the first byte to patch.
+    position: usize,
+    /// Address just after the instruction or data item containing the reference.
+    origin: u16,
+    relocation: Relocation,
+    /// Source text of the operand.
+    text: &'a str,
+    expr: Expr<'a>,
+    /// Values of the symbols that were already defined where the reference was made.
+    bindings: Vec<(&'a str, u16)>,
+    /// Global label that local labels in the operand belong to.
+    scope: &'a str,
+    line: usize,
+}
+
+impl Erratum<'_> {
+    fn apply(&self, output: &mut [u8], value: u16) -> Result<(), CompileError> {
+        self.relocation
+            .patch(output, self.position, self.origin, value, self.text)
+    }
+}
+
+/// An `.equ` to be defined once the symbols it refers to are.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Equate<'a> {
+    name: &'a str,
+    expr: Expr<'a>,
+    /// Values of the symbols that were already defined where the `.equ` was.
+    bindings: Vec<(&'a str, u16)>,
+    /// Global label that local labels in the `.equ` belong to.
+    scope: &'a str,
+    line: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Value<'a> {
+    Literal(u16),
+    /// An expression that refers to symbols, kept as source text.
+    Expression(&'a str),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Operand<'a> {
+    /// `A`, `B`, `C` or `D`.
+    Register(GeneralPurposeRegister),
+    /// `value` or `#value`.
+    Immediate(Value<'a>),
+    /// `[B]`.
+    Indirect,
+    /// `[B+]`.
+    IndirectPostInc,
+    /// `[value]`.
+    Address(Value<'a>),
+    /// `[B+value]`.
+    Offset(Value<'a>),
+    /// `[SP+value]`.
+    StackOffset(Value<'a>),
+}
+
+/// Lines after macro expansion, each with the number of the source line it came from.
+type Lines = Vec<(usize, String)>;
+
+/// A `.macro` definition.
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+struct Assembler<'a> {
+    output: Vec<u8>,
+    address: u16,
+    symbols: HashMap<String, u16>,
+    errata: Vec<Erratum<'a>>,
+    /// `.equ` symbols whose values refer to symbols not defined yet.
+    equates: Vec<Equate<'a>>,
+    /// The last global label defined, which local labels (those starting with `.`) belong to.
+    scope: &'a str,
+    options: AssemblerOptions,
+    /// Every label defined, with the name it was written as and its line, to warn about those never used.
+    labels: Vec<(String, &'a str, usize)>,
+    /// Keys of every symbol referred to.
+    used: HashSet<String>,
+    warnings: Vec<(usize, CompileWarning)>,
+    /// For each byte of `output`, the line that wrote it, or 0 if none has.
+    writers: Vec<usize>,
+    /// The line being assembled.
+    line: usize,
+    /// Every run of bytes emitted: the line, the address, and the length.
+    emitted: Vec<(usize, u16, usize)>,
+    /// Every section used. The `end` of the current one is only brought up to date when it is left.
+    sections: Vec<Section>,
+    /// Index into `sections` of the section being assembled, whose address is `address`.
+    section: usize,
+}
+
+/// Assemble `source` into a memory image starting at [`START_ADDRESS`], with the default [`AssemblerOptions`].
+///
+/// A line with an error is skipped and assembly carries on, so every error in the source is returned at once, in
+/// order of line. Errors inside a macro expansion are reported on the line of the invocation; a malformed macro
+/// definition stops assembly on its own.
+///
+/// Symbols are identifiers: a letter, `_` or `.` followed by letters, digits, `_` and `.`. Those starting with `.` are
+/// local labels, belonging to the global label before them. `A`, `B`, `C`, `D` and `SP` are reserved.
+///
+/// The operand of a relative branch (`JR`, `LOOPR`, `CALLR`, `DJNZ`) is its target, whether it is a label, a constant
+/// or a number; the offset from the end of the instruction is worked out from it. Write `$+8` for a target 8 bytes
+/// past the start of the branch.
+///
+/// Output goes to the `.text` section, starting at the [origin](AssemblerOptions::origin), until
+/// `.section name, address` starts another section at `address`. `.section name` returns to a section where it left
+/// off, so code and data can be interleaved in the source. Sections named `.bss` or `.bss.*` only reserve space with
+/// `.space` and `.align`, and add nothing to the image.
+///
+/// On success, returns the image together with any warnings.
+pub fn compile(source: &str) -> Result<(Vec<u8>, Vec<Warning>), Vec<Diagnostic>> {
+    compile_with(source, &AssemblerOptions::default())
+}
+
+/// Assemble `source` like [`compile`], with the given options.
+pub fn compile_with(
+    source: &str,
+    options: &AssemblerOptions,
+) -> Result<(Vec<u8>, Vec<Warning>), Vec<Diagnostic>> {
+    compile_with_symbols(source, options).map(|(image, _, warnings)| (image, warnings))
+}
+
+/// Assemble `source` like [`compile_with`], also returning the symbol table: every label and `.def` constant with its
+/// final value, sorted by value and then name. Local labels are given their full name, such as `print.loop`.
+pub fn compile_with_symbols(
+    source: &str,
+    options: &AssemblerOptions,
+) -> Result<(Vec<u8>, Symbols, Vec<Warning>), Vec<Diagnostic>> {
+    assemble(source, options).map(|artifact| (artifact.image, artifact.symbols, artifact.warnings))
+}
+
+/// Assemble `source` like [`compile_with`], returning a listing instead of the image: every source line, with the
+/// address and bytes of what it assembled to alongside.
+pub fn compile_listing(
+    source: &str,
+    options: &AssemblerOptions,
+) -> Result<String, Vec<Diagnostic>> {
+    assemble(source, options).map(|artifact| artifact.listing(source))
+}
+
+/// Everything [`assemble`] produces from a source file, for tools that need more than the image.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Artifact {
+    /// The memory image, starting at `origin`.
+    pub image: Vec<u8>,
+    pub origin: u16,
+    /// The symbol table, as [`compile_with_symbols`] returns it.
+    pub symbols: Symbols,
+    pub warnings: Vec<Warning>,
+    /// Every run of bytes emitted, in order: the source line, the address, and the length. A line emits more than
+    /// once if it is a macro invocation or inside a `.rept` block.
+    pub lines: Vec<(usize, u16, usize)>,
+    /// Every section, in order of first use.
+    pub sections: Vec<Section>,
+}
+
+impl Artifact {
+    /// A listing of `source`, which this was assembled from, as [`compile_listing`] returns it.
+    pub fn listing(&self, source: &str) -> String {
+        // Source lines may emit several times, such as a macro invocation or a `.rept` block.
+        let mut emitted: Vec<Vec<(u16, usize)>> = vec![Vec::new(); source.lines().count() + 1];
+        for &(line, address, length) in &self.lines {
+            // Join output that follows on from the line's last, such as each operand of a `.db` or each repetition
+            // in a `.rept` block, into one run.
+            match emitted[line].last_mut() {
+                Some((start, run)) if start.wrapping_add(*run as u16) == address => *run += length,
+                _ => emitted[line].push((address, length)),
+            }
+        }
+
+        let mut listing = String::new();
+        for (index, text) in source.lines().enumerate() {
+            let mut rows = Vec::new();
+            for &(address, length) in &emitted[index + 1] {
+                let position = address.wrapping_sub(self.origin) as usize;
+                let bytes = &self.image[position..position + length];
+                for (row, chunk) in bytes.chunks(LISTING_BYTES_PER_ROW).enumerate() {
+                    let hex: Vec<String> = chunk.iter().map(|byte| format!("{byte:02X}")).collect();
+                    let address = address.wrapping_add((row * LISTING_BYTES_PER_ROW) as u16);
+                    rows.push(format!("{address:04X}  {:<11}", hex.join(" ")));
+                }
+            }
+            let first = rows.first().map_or(format!("{:17}", ""), String::clone);
+            let first = format!("{:>5}  {first}  {text}", index + 1);
+            listing.push_str(first.trim_end());
+            listing.push('\n');
+            for row in rows.iter().skip(1) {
+                listing.push_str(&format!("{:5}  {}\n", "", row.trim_end()));
+            }
+        }
+        listing
+    }
+
+    /// A line table for `source`, which this was assembled from, pointing each run of bytes at the statement that
+    /// emitted it.
+    pub fn line_table(&self, source: &str) -> LineTable {
+        let lines: Vec<&str> = source.lines().collect();
+        let entries = self
+            .lines
+            .iter()
+            .map(|&(line, address, length)| LineEntry {
+                address,
+                length: length as u16,
+                line: line as u32,
+                column: lines.get(line - 1).map_or(1, |text| statement_column(text)) as u16,
+            })
+            .collect();
+        LineTable::new(entries)
+    }
+
+    /// The bytes of `section` in the image. Uninitialized sections have none.
+    pub fn data(&self, section: &Section) -> &[u8] {
+        if section.uninitialized {
+            return &[];
+        }
+        let start = (section.start.wrapping_sub(self.origin) as usize).min(self.image.len());
+        let end = section
+            .end
+            .saturating_sub(self.origin as usize)
+            .clamp(start, self.image.len());
+        &self.image[start..end]
+    }
+}
+
+/// A section of the output, from `.section`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Section {
+    pub name: String,
+    /// Address the section starts at.
+    pub start: u16,
+    /// Address just after the highest byte the section holds or reserves, or `start` if it has none. A backwards
+    /// `.org` does not lower it, and it is $10000 for a section that reaches the top of memory.
+    pub end: usize,
+    /// Bytes the section holds, or reserves if it is uninitialized. Gaps left by `.org` are not counted.
+    pub size: usize,
+    /// Whether the section only reserves space, like `.bss`, rather than holding bytes for the image.
+    pub uninitialized: bool,
+    /// Address to continue at when `.section` returns to the section.
+    address: u16,
+}
+
+impl Section {
+    fn new(name: &str, start: u16) -> Self {
+        Self {
+            name: name.to_string(),
+            start,
+            end: start as usize,
+            size: 0,
+            uninitialized: name == ".bss" || name.starts_with(".bss."),
+            address: start,
+        }
+    }
+}
+
+/// Assemble `source` like [`compile_with`], returning the image together with everything known about it: the symbol
+/// table, which lines produced which bytes, and the sections.
+pub fn assemble(source: &str, options: &AssemblerOptions) -> Result<Artifact, Vec<Diagnostic>> {
+    let source = normalize(source, options);
+    let source = source.as_ref();
+    let lines = expand_macros(source).map_err(|(line, kind)| {
+        vec![Diagnostic {
+            line,
+            column: column(source, line, kind.token()),
+            kind,
+        }]
+    })?;
+    let mut errors = Vec::new();
+    let mut assembler = Assembler {
+        output: Vec::new(),
+        address: options.origin,
+        symbols: HashMap::new(),
+        errata: Vec::new(),
+        equates: Vec::new(),
+        scope: "",
+        options: options.clone(),
+        labels: Vec::new(),
+        used: HashSet::new(),
+        warnings: Vec::new(),
+        writers: Vec::new(),
+        line: 0,
+        emitted: Vec::new(),
+        sections: vec![Section::new(DEFAULT_SECTION, options.origin)],
+        section: 0,
+    };
+
+    for (name, value) in &options.defines {
+        let defined = if is_identifier(name) {
+            assembler.define(name, *value)
+        } else {
+            Err(CompileError::InvalidOperand(name.clone()))
+        };
+        if let Err(err) = defined {
+            errors.push((None, err));
+        }
+    }
+
+    for (line_number, line) in &lines {
+        if let Err(err) = assembler.parse_line(line, *line_number) {
+            errors.push((Some(*line_number), err));
+        }
+    }
+
+    // An equate can refer to others, so keep going until a round defines nothing new.
+    let mut equates = std::mem::take(&mut assembler.equates);
+    loop {
+        let count = equates.len();
+        equates.retain(|equate| {
+            let value = equate.expr.evaluate(&|symbol| {
+                equate
+                    .bindings
+                    .iter()
+                    .find(|(name, _)| *name == symbol)
+                    .map(|&(_, value)| value)
+                    .or_else(|| {
+                        let name = assembler.key(equate.scope, symbol);
+                        assembler.symbols.get(&name).copied()
+                    })
+            });
+            let defined = match value {
+                Ok(value) => {
+                    let key = assembler.key(equate.scope, equate.name);
+                    assembler.equate(key, equate.name, value)
+                }
+                Err(CompileError::UndefinedSymbol(_)) => return true,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = defined {
+                errors.push((Some(equate.line), err));
+            }
+            false
+        });
+        if equates.len() == count {
+            break;
+        }
+    }
+    // Whatever is left refers to symbols that are never defined, or to each other.
+    for equate in equates {
+        let mut symbols = Vec::new();
+        equate.expr.symbols(&mut symbols);
+        let undefined = symbols
+            .into_iter()
+            .find(|symbol| {
+                !assembler
+                    .symbols
+                    .contains_key(&assembler.key(equate.scope, symbol))
+            })
+            .unwrap_or(equate.name);
+        errors.push((
+            Some(equate.line),
+            CompileError::UndefinedSymbol(undefined.to_string()),
+        ));
+    }
+
+    // Symbols take the value they had where they were used; forward references take their final value.
+    for erratum in &assembler.errata {
+        let patched = erratum
+            .expr
+            .evaluate(&|symbol| {
+                erratum
+                    .bindings
+                    .iter()
+                    .find(|(name, _)| *name == symbol)
+                    .map(|&(_, value)| value)
+                    .or_else(|| {
+                        let name = assembler.key(erratum.scope, symbol);
+                        assembler.symbols.get(&name).copied()
+                    })
+            })
+            .and_then(|value| {
+                erratum.apply(&mut assembler.output, value)?;
+                Ok(value)
+            });
+        match patched {
+            Ok(value)
+                if erratum.relocation == Relocation::Absolute8
+                    && value >= 0xFF80
+                    && !matches!(erratum.expr, Expr::Negate(_)) =>
+            {
+                let warning = CompileWarning::Truncated(erratum.text.to_string(), value);
+                assembler.warnings.push((erratum.line, warning));
+            }
+            Ok(_) => {}
+            Err(err) => errors.push((Some(erratum.line), err)),
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.sort_by_key(|&(line, _)| line);
+        return Err(errors
+            .into_iter()
+            .map(|(line, kind)| Diagnostic {
+                line,
+                column: column(source, line, kind.token()),
+                kind,
+            })
+            .collect());
+    }
+
+    for (key, label, line) in &assembler.labels {
+        if !assembler.used.contains(key) {
+            let warning = CompileWarning::UnusedLabel(label.to_string());
+            assembler.warnings.push((*line, warning));
+        }
+    }
+    assembler.warnings.sort_by_key(|&(line, _)| line);
+    let warnings = assembler
+        .warnings
+        .into_iter()
+        .map(|(line, kind)| Diagnostic {
+            line: Some(line),
+            column: column(source, Some(line), kind.token()),
+            kind,
+        })
+        .collect();
+    let mut symbols: Symbols = assembler
+        .symbols
+        .into_iter()
+        .filter(|(name, _)| name != HERE)
+        .collect();
+    symbols.sort_by(|(a, a_value), (b, b_value)| a_value.cmp(b_value).then_with(|| a.cmp(b)));
+    Ok(Artifact {
+        image: assembler.output,
+        origin: options.origin,
+        symbols,
+        warnings,
+        lines: assembler.emitted,
+        sections: assembler.sections,
+    })
+}
+
+impl<'a> Assembler<'a> {
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), CompileError> {
+        self.check_output()?;
+        let start = self.position();
+        let end = start + bytes.len();
+        if self.output.len() < end {
+            self.output.resize(end, 0);
+            self.writers.resize(end, 0);
+        }
+        if let Some(offset) = self.writers[start..end].iter().position(|&line| line != 0) {
+            let address = self.address.wrapping_add(offset as u16);
+            return Err(CompileError::Overlap(address, self.writers[start + offset]));
+        }
+        self.output[start..end].copy_from_slice(bytes);
+        self.writers[start..end].fill(self.line);
+        if !bytes.is_empty() {
+            self.emitted.push((self.line, self.address, bytes.len()));
+            self.extend_section(bytes.len());
+        }
+        self.address = self.address.wrapping_add(bytes.len() as u16);
+        Ok(())
+    }
+
+    /// Emit `count` copies of `byte`, or in an uninitialized section, skip `count` bytes.
+    fn reserve(&mut self, count: usize, byte: u8) -> Result<(), CompileError> {
+        if self.sections[self.section].uninitialized && byte == 0 {
+            self.extend_section(count);
+            self.address = self.address.wrapping_add(count as u16);
+            Ok(())
+        } else {
+            self.emit(&vec![byte; count])
+        }
+    }
+
+    /// Count `length` bytes from the current address into the current section.
+    fn extend_section(&mut self, length: usize) {
+        let section = &mut self.sections[self.section];
+        section.size += length;
+        if length > 0 {
+            section.end = section.end.max(self.address as usize + length);
+        }
+    }
+
+    /// Check that output can be written at the current address.
+    fn check_output(&self) -> Result<(), CompileError> {
+        let section = &self.sections[self.section];
+        if section.uninitialized {
+            return Err(CompileError::InitializedData(section.name.clone()));
+        }
+        if self.address < self.options.origin {
+            return Err(CompileError::BelowOrigin(self.address, self.options.origin));
+        }
+        Ok(())
+    }
+
+    fn position(&self) -> usize {
+        self.address.wrapping_sub(self.options.origin) as usize
+    }
+
+    /// Record a reference to the expression `text` at `offset` bytes into the item about to be emitted.
+    fn refer(
+        &mut self,
+        text: &'a str,
+        relocation: Relocation,
+        offset: usize,
+        length: usize,
+        line: usize,
+    ) -> Result<(), CompileError> {
+        self.check_output()?;
+        let expr = Expr::parse(text)?;
+        self.mark_used(&expr);
+        let mut symbols = Vec::new();
+        expr.symbols(&mut symbols);
+        let bindings = symbols
+            .into_iter()
+            .filter_map(|symbol| Some((symbol, self.lookup(symbol)?)))
+            .collect();
+        self.errata.push(Erratum {
+            position: self.position() + offset,
+            origin: self.address.wrapping_add(length as u16),
+            relocation,
+            text,
+            expr,
+            bindings,
+            scope: self.scope,
+            line,
+        });
+        Ok(())
+    }
+
+    /// The key the symbol `name`, written under the global label `scope`, is stored under in `symbols`.
+    fn key(&self, scope: &str, name: &str) -> String {
+        let name = qualify(scope, name);
+        if self.options.ignore_case {
+            name.to_ascii_uppercase()
+        } else {
+            name
+        }
+    }
+
+    fn lookup(&self, symbol: &str) -> Option<u16> {
+        self.symbols.get(&self.key(self.scope, symbol)).copied()
+    }
+
+    /// Define or change the symbol `name`, written in the current scope.
+    fn define(&mut self, name: &str, value: u16) -> Result<(), CompileError> {
+        if is_reserved(name) {
+            return Err(CompileError::ReservedWord(name.to_string()));
+        }
+        self.symbols.insert(self.key(self.scope, name), value);
+        Ok(())
+    }
+
+    /// Define the symbol with key `key`, written `name`, unless it already has a different value.
+    fn equate(&mut self, key: String, name: &str, value: u16) -> Result<(), CompileError> {
+        match self.symbols.get(&key) {
+            Some(&existing) if existing != value => {
+                Err(CompileError::Redefinition(name.to_string()))
+            }
+            _ => {
+                self.symbols.insert(key, value);
+                Ok(())
+            }
+        }
+    }
+
+    fn mark_used(&mut self, expr: &Expr<'_>) {
+        let mut symbols = Vec::new();
+        expr.symbols(&mut symbols);
+        for symbol in symbols {
+            let key = self.key(self.scope, symbol);
+            self.used.insert(key);
+        }
+    }
+
+    fn resolve(&mut self, value: Value<'_>) -> Result<u16, CompileError> {
+        match value {
+            Value::Literal(value) => Ok(value),
+            Value::Expression(text) => {
+                let expr = Expr::parse(text)?;
+                self.mark_used(&expr);
+                expr.evaluate(&|symbol| self.lookup(symbol))
+            }
+        }
+    }
+
+    fn parse_line(&mut self, line: &'a str, line_number: usize) -> Result<(), CompileError> {
+        let mut line = strip_comment(line).trim();
+        self.line = line_number;
+        self.symbols.insert(HERE.to_string(), self.address);
+
+        if let Some((label, rest)) = line.split_once(':')
+            && is_identifier(label.trim())
+        {
+            let label = label.trim();
+            if !label.starts_with('.') {
+                self.scope = label;
+            }
+            let key = self.key(self.scope, label);
+            if self.symbols.contains_key(&key) {
+                return Err(match self.labels.iter().find(|(name, ..)| *name == key) {
+                    Some(&(_, _, first)) => CompileError::DuplicateLabel(label.to_string(), first),
+                    None => CompileError::LabelRedefinition(label.to_string()),
+                });
+            }
+            self.define(label, self.address)?;
+            self.labels.push((key, label, line_number));
+            line = rest.trim();
+        }
+
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, rest) = split_mnemonic(line);
+
+        if mnemonic.starts_with('.') {
+            return self.parse_directive(mnemonic, rest, line_number);
+        }
+
+        let operands = split_operands(rest);
+        let (instruction, symbol) =
+            parse_instruction(&mnemonic.to_ascii_uppercase(), &operands, self.address).map_err(
+                |err| match err {
+                    CompileError::InvalidInstruction(_) => {
+                        CompileError::InvalidInstruction(line.to_string())
+                    }
+                    err => err,
+                },
+            )?;
+
+        let bytes = Vec::from(instruction);
+        if let Some((symbol, relocation)) = symbol {
+            // Operands are always the last bytes of an instruction.
+            let offset = bytes.len() - relocation.size();
+            self.refer(symbol, relocation, offset, bytes.len(), line_number)?;
+        }
+        self.emit(&bytes)?;
+        Ok(())
+    }
+
+    fn parse_directive(
+        &mut self,
+        directive: &str,
+        rest: &'a str,
+        line_number: usize,
+    ) -> Result<(), CompileError> {
+        match directive.to_ascii_lowercase().as_str() {
+            ".org" => {
+                let address = self.resolve(parse_value(rest)?)?;
+                if address < self.address {
+                    let warning = CompileWarning::OrgBackwards(self.address, address);
+                    self.warnings.push((line_number, warning));
+                }
+                self.address = address;
+            }
+            // `.section name [, address]`: carry on in section `name`, which starts at `address` the first time.
+            ".section" => {
+                let (name, start) = match split_operands(rest).as_slice() {
+                    [name] => (*name, None),
+                    [name, start] => (*name, Some(*start)),
+                    _ => return Err(CompileError::InvalidOperand(rest.to_string())),
+                };
+                if !is_identifier(name) {
+                    return Err(CompileError::InvalidOperand(name.to_string()));
+                }
+                let existing = self
+                    .sections
+                    .iter()
+                    .position(|section| section.name == name);
+                let section = match (existing, start) {
+                    (Some(index), None) => index,
+                    (None, Some(start)) => {
+                        let start = self.resolve(parse_value(start)?)?;
+                        self.sections.push(Section::new(name, start));
+                        self.sections.len() - 1
+                    }
+                    // The start of a section is only given where it is first used.
+                    (Some(_), Some(start)) => {
+                        return Err(CompileError::InvalidOperand(start.to_string()));
+                    }
+                    (None, None) => return Err(CompileError::InvalidOperand(rest.to_string())),
+                };
+                self.sections[self.section].address = self.address;
+                self.section = section;
+                self.address = self.sections[section].address;
+            }
+            // `.equ name value`: like `.def`, but `value` may refer to symbols defined later, and defining the same
+            // value again is allowed.
+            ".equ" => {
+                let (name, value) = rest
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| CompileError::InvalidOperand(rest.to_string()))?;
+                if !is_identifier(name) {
+                    return Err(CompileError::InvalidOperand(name.to_string()));
+                }
+                if is_reserved(name) {
+                    return Err(CompileError::ReservedWord(name.to_string()));
+                }
+                let key = self.key(self.scope, name);
+                let expr = Expr::parse(value.trim())?;
+                self.mark_used(&expr);
+                match expr.evaluate(&|symbol| self.lookup(symbol)) {
+                    Ok(value) => self.equate(key, name, value)?,
+                    Err(CompileError::UndefinedSymbol(_)) => {
+                        let mut symbols = Vec::new();
+                        expr.symbols(&mut symbols);
+                        let bindings = symbols
+                            .into_iter()
+                            .filter_map(|symbol| Some((symbol, self.lookup(symbol)?)))
+                            .collect();
+                        self.equates.push(Equate {
+                            name,
+                            expr,
+                            bindings,
+                            scope: self.scope,
+                            line: line_number,
+                        });
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            ".def" | ".redef" | ".set" => {
+                let (name, value) = rest
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| CompileError::InvalidOperand(rest.to_string()))?;
+                if !is_identifier(name) {
+                    return Err(CompileError::InvalidOperand(name.to_string()));
+                }
+                if directive.eq_ignore_ascii_case(".def") && self.lookup(name).is_some() {
+                    return Err(CompileError::Redefinition(name.to_string()));
+                }
+                let value = self.resolve(parse_value(value.trim())?)?;
+                self.define(name, value)?;
+            }
+            ".undef" => {
+                if self.symbols.remove(&self.key(self.scope, rest)).is_none() {
+                    return Err(CompileError::UndefinedSymbol(rest.to_string()));
+                }
+            }
+            ".db" => {
+                for operand in rest.split(',') {
+                    match parse_value(operand.trim())? {
+                        Value::Literal(value) if (0x0100..0xFF80).contains(&value) => {
+                            return Err(CompileError::InvalidOperand(operand.trim().to_string()));
+                        }
+                        Value::Literal(value) => self.emit(&[value as u8])?,
+                        Value::Expression(text) => {
+                            self.refer(text, Relocation::Absolute8, 0, 1, line_number)?;
+                            self.emit(&[0])?;
+                        }
+                    }
+                }
+            }
+            ".dw" => {
+                for operand in rest.split(',') {
+                    match parse_value(operand.trim())? {
+                        Value::Literal(value) => self.emit(&value.to_le_bytes())?,
+                        Value::Expression(text) => {
+                            self.refer(text, Relocation::Absolute16, 0, 2, line_number)?;
+                            self.emit(&[0, 0])?;
+                        }
+                    }
+                }
+            }
+            // Pad with zeros up to the next multiple of the operand.
+            ".align" => {
+                let alignment = self.resolve(parse_value(rest)?)? as usize;
+                if alignment == 0 {
+                    return Err(CompileError::InvalidOperand(rest.to_string()));
+                }
+                let padding = (alignment - self.address as usize % alignment) % alignment;
+                self.reserve(padding, 0)?;
+            }
+            // `.space count [, byte]`: `count` copies of `byte`, or of zero.
+            ".space" => {
+                let (count, byte) = match split_operands(rest).as_slice() {
+                    [count] => (self.resolve(parse_value(count)?)?, 0),
+                    [count, byte] => {
+                        let value = self.resolve(parse_value(byte)?)?;
+                        if (0x0100..0xFF80).contains(&value) {
+                            return Err(CompileError::InvalidOperand(byte.to_string()));
+                        }
+                        (self.resolve(parse_value(count)?)?, value as u8)
+                    }
+                    _ => return Err(CompileError::InvalidOperand(rest.to_string())),
+                };
+                self.reserve(count as usize, byte)?;
+            }
+            // `.fill count, value`: `count` copies of the word `value`.
+            ".fill" => {
+                let [count, value] = split_operands(rest)[..] else {
+                    return Err(CompileError::InvalidOperand(rest.to_string()));
+                };
+                let count = self.resolve(parse_value(count)?)?;
+                let value = self.resolve(parse_value(value)?)?;
+                self.emit(&value.to_le_bytes().repeat(count as usize))?;
+            }
+            ".ascii" => {
+                self.emit(&parse_string(rest)?)?;
+            }
+            ".asciiz" => {
+                let mut bytes = parse_string(rest)?;
+                bytes.push(0);
+                self.emit(&bytes)?;
+            }
+            ".incbin" => {
+                let (path, range) = rest
+                    .strip_prefix('"')
+                    .and_then(|rest| rest.split_once('"'))
+                    .ok_or_else(|| CompileError::InvalidString(rest.to_string()))?;
+                let range = range.trim();
+                let range = match range.strip_prefix(',') {
+                    Some(range) => split_operands(range.trim()),
+                    None if range.is_empty() => Vec::new(),
+                    None => return Err(CompileError::InvalidOperand(range.to_string())),
+                };
+                let bytes = std::fs::read(path).map_err(|err| {
+                    CompileError::IncludeFailed(path.to_string(), err.to_string())
+                })?;
+                let (offset, length) = match range.as_slice() {
+                    [] => (0, bytes.len()),
+                    [offset] => {
+                        let offset = self.resolve(parse_value(offset)?)? as usize;
+                        (offset, bytes.len().saturating_sub(offset))
+                    }
+                    [offset, length] => (
+                        self.resolve(parse_value(offset)?)? as usize,
+                        self.resolve(parse_value(length)?)? as usize,
+                    ),
+                    _ => return Err(CompileError::InvalidOperand(rest.to_string())),
+                };
+                let bytes = bytes
+                    .get(offset..offset + length)
+                    .ok_or_else(|| CompileError::InvalidOperand(rest.to_string()))?;
+                self.emit(bytes)?;
+            }
+            _ => return Err(CompileError::UnknownDirective(directive.to_string())),
+        }
+        Ok(())
+    }
+}
+
+/// Column of `token` on `line` of `source`, starting from 1.
+fn column(source: &str, line: Option<usize>, token: Option<&str>) -> Option<usize> {
+    line.and_then(|line| source.lines().nth(line - 1))
+        .zip(token)
+        .and_then(|(text, token)| find_token(strip_comment(text), token))
+        .map(|index| index + 1)
+}
+
+/// Column of the statement on `text`, after any label.
+fn statement_column(text: &str) -> usize {
+    let start = match text.split_once(':') {
+        Some((label, _)) if is_identifier(label.trim()) => label.len() + 1,
+        _ => 0,
+    };
+    let rest = &text[start..];
+    start + rest.len() - rest.trim_start().len() + 1
+}
+
+/// Index of the first occurrence of `token` in `text` that is not part of a longer identifier.
+fn find_token(text: &str, token: &str) -> Option<usize> {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+    text.match_indices(token)
+        .map(|(index, _)| index)
+        .find(|&index| {
+            let before = text[..index].chars().next_back();
+            let after = text[index + token.len()..].chars().next();
+            !(token.starts_with(is_word) && before.is_some_and(is_word))
+                && !(token.ends_with(is_word) && after.is_some_and(is_word))
+        })
+}
+
+/// Expand `.macro` ... `.endmacro` definitions and their invocations, and `.rept` ... `.endr` blocks, returning the
+/// resulting lines together with the source line each came from.
+///
+/// `\name` in a macro body is replaced by the argument for parameter `name`, and `\@` by a number unique to each
+/// expansion, for labels such as `loop_\@`. A macro must be defined before it is used.
+///
+/// `.rept count[, counter]` repeats the lines up to the matching `.endr` `count` times. The count must be a constant,
+/// as symbols are not known yet. If `counter` is given, it is defined as a symbol holding the iteration number,
+/// starting from 0, at the start of each repetition.
+fn expand_macros(source: &str) -> Result<Lines, LineError> {
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut expansions = 0;
+    let mut output = Vec::new();
+    let mut lines = source
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line));
+
+    while let Some((line_number, line)) = lines.next() {
+        let (directive, rest) = split_mnemonic(strip_comment(line).trim());
+        if directive.eq_ignore_ascii_case(".rept") {
+            let mut block = vec![(line_number, line.to_string())];
+            let mut nesting = 1;
+            while nesting > 0 {
+                let Some((line_number, line)) = lines.next() else {
+                    break;
+                };
+                nesting += block_nesting(line);
+                block.push((line_number, line.to_string()));
+            }
+            expand_lines(&macros, &block, &mut expansions, 0, &mut output)?;
+            continue;
+        }
+        if !directive.eq_ignore_ascii_case(".macro") {
+            expand_line(&macros, line, line_number, &mut expansions, 0, &mut output)?;
+            continue;
+        }
+
+        let (name, params) = split_mnemonic(rest);
+        let params = split_operands(params);
+        if let Some(invalid) = std::iter::once(name)
+            .chain(params.iter().copied())
+            .find(|name| !is_identifier(name))
+        {
+            return Err((
+                Some(line_number),
+                CompileError::InvalidOperand(invalid.to_string()),
+            ));
+        }
+        let mut body = Vec::new();
+        loop {
+            let Some((_, line)) = lines.next() else {
+                return Err((
+                    Some(line_number),
+                    CompileError::UnterminatedMacro(name.to_string()),
+                ));
+            };
+            if split_mnemonic(strip_comment(line).trim())
+                .0
+                .eq_ignore_ascii_case(".endmacro")
+            {
+                break;
+            }
+            body.push(line.to_string());
+        }
+        macros.insert(
+            name.to_ascii_uppercase(),
+            Macro {
+                params: params.iter().map(|param| param.to_string()).collect(),
+                body,
+            },
+        );
+    }
+    Ok(output)
+}
+
+/// How a line changes the nesting of `.rept` blocks: 1 for `.rept`, -1 for `.endr`, and 0 otherwise.
+fn block_nesting(line: &str) -> i32 {
+    let directive = split_mnemonic(strip_comment(line).trim()).0;
+    if directive.eq_ignore_ascii_case(".rept") {
+        1
+    } else if directive.eq_ignore_ascii_case(".endr") {
+        -1
+    } else {
+        0
+    }
+}
+
+/// Append `lines` to `output`, expanding macro invocations and `.rept` blocks.
+fn expand_lines(
+    macros: &HashMap<String, Macro>,
+    lines: &[(usize, String)],
+    expansions: &mut usize,
+    depth: usize,
+    output: &mut Lines,
+) -> Result<(), LineError> {
+    let mut index = 0;
+    while let Some((line_number, line)) = lines.get(index) {
+        let (directive, rest) = split_mnemonic(strip_comment(line).trim());
+        if !directive.eq_ignore_ascii_case(".rept") {
+            expand_line(macros, line, *line_number, expansions, depth, output)?;
+            index += 1;
+            continue;
+        }
+
+        let mut nesting = 1;
+        let end = (index + 1..lines.len())
+            .find(|&end| {
+                nesting += block_nesting(&lines[end].1);
+                nesting == 0
+            })
+            .ok_or((Some(*line_number), CompileError::UnterminatedRepeat))?;
+        let (count, counter) = match split_operands(rest).as_slice() {
+            [count] => (*count, None),
+            [count, counter] if is_identifier(counter) => (*count, Some(*counter)),
+            _ => {
+                return Err((
+                    Some(*line_number),
+                    CompileError::InvalidOperand(rest.to_string()),
+                ));
+            }
+        };
+        let Ok(Value::Literal(count)) = parse_value(count) else {
+            return Err((
+                Some(*line_number),
+                CompileError::InvalidOperand(count.to_string()),
+            ));
+        };
+        for iteration in 0..count {
+            if let Some(counter) = counter {
+                output.push((*line_number, format!(".redef {counter} {iteration}")));
+            }
+            expand_lines(macros, &lines[index + 1..end], expansions, depth, output)?;
+        }
+        index = end + 1;
+    }
+    Ok(())
+}
+
+/// Append `line` to `output`, expanding it first if it invokes a macro.
+fn expand_line(
+    macros: &HashMap<String, Macro>,
+    line: &str,
+    line_number: usize,
+    expansions: &mut usize,
+    depth: usize,
+    output: &mut Lines,
+) -> Result<(), LineError> {
+    let code = strip_comment(line).trim();
+    let (label, code) = match code.split_once(':') {
+        Some((label, rest)) if is_identifier(label.trim()) => (Some(label.trim()), rest.trim()),
+        _ => (None, code),
+    };
+    let (name, rest) = split_mnemonic(code);
+    let Some(definition) = macros.get(&name.to_ascii_uppercase()) else {
+        output.push((line_number, line.to_string()));
+        return Ok(());
+    };
+    if depth >= MAX_MACRO_DEPTH {
+        return Err((
+            Some(line_number),
+            CompileError::MacroRecursion(name.to_string()),
+        ));
+    }
+    let args = split_operands(rest);
+    if args.len() != definition.params.len() {
+        return Err((
+            Some(line_number),
+            CompileError::InvalidInstruction(code.to_string()),
+        ));
+    }
+
+    if let Some(label) = label {
+        output.push((line_number, format!("{label}:")));
+    }
+    *expansions += 1;
+    let id = expansions.to_string();
+    let body: Lines = definition
+        .body
+        .iter()
+        .map(|body_line| {
+            let body_line = substitute(body_line, &definition.params, &args, &id);
+            (line_number, body_line)
+        })
+        .collect();
+    expand_lines(macros, &body, expansions, depth + 1, output)
+}
+
+/// Replace `\param` with the matching argument and `\@` with `id`. Other backslashes are left alone.
+fn substitute(line: &str, params: &[String], args: &[&str], id: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(index) = rest.find('\\') {
+        result.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        if let Some(after) = after.strip_prefix('@') {
+            result.push_str(id);
+            rest = after;
+            continue;
+        }
+        let end = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        match params.iter().position(|param| *param == after[..end]) {
+            Some(position) => {
+                result.push_str(args[position]);
+                rest = &after[end..];
+            }
+            None => {
+                result.push('\\');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Split a line into its mnemonic or directive and the rest.
+fn split_mnemonic(line: &str) -> (&str, &str) {
+    line.split_once(char::is_whitespace)
+        .map_or((line, ""), |(mnemonic, rest)| (mnemonic, rest.trim()))
+}
+
+/// Split comma-separated operands.
+fn split_operands(rest: &str) -> Vec<&str> {
+    if rest.is_empty() {
+        Vec::new()
+    } else {
+        rest.split(',').map(str::trim).collect()
+    }
+}
+
+/// Build the instruction for `mnemonic` (already uppercased) and `operands`, to be placed at `address`.
+///
+/// Operands referring to symbols are encoded as zero; the symbol is returned with its relocation so the caller can
+/// patch it later.
+fn parse_instruction<'a>(
+    mnemonic: &str,
+    operands: &[&'a str],
+    address: u16,
+) -> Result<(Instruction, Fixup<'a>), CompileError> {
+    use Instruction::*;
+    use Operand::*;
+
+    fn with<'a>(value: Value<'a>, f: impl FnOnce(u16) -> Instruction) -> (Instruction, Fixup<'a>) {
+        match value {
+            Value::Literal(value) => (f(value), None),
+            Value::Expression(text) => (f(0), Some((text, Relocation::Absolute16))),
+        }
+    }
+
+    /// Like `with`, for byte operands.
+    fn byte<'a>(
+        value: Value<'a>,
+        f: impl FnOnce(u8) -> Instruction,
+    ) -> Result<(Instruction, Fixup<'a>), CompileError> {
+        match value {
+            Value::Literal(value) if (0x0100..0xFF80).contains(&value) => {
+                Err(CompileError::InvalidOperand(value.to_string()))
+            }
+            Value::Literal(value) => Ok((f(value as u8), None)),
+            Value::Expression(text) => Ok((f(0), Some((text, Relocation::Absolute8)))),
+        }
+    }
+
+    /// Like `with`, for the target of a branch at `address`, which is encoded relative to the end of the
+    /// instruction. A literal is a target like any other value.
+    fn relative<'a>(
+        value: Value<'a>,
+        address: u16,
+        f: impl Fn(u16) -> Instruction,
+    ) -> (Instruction, Fixup<'a>) {
+        match value {
+            Value::Literal(target) => {
+                let end = address.wrapping_add(Vec::from(f(0)).len() as u16);
+                (f(target.wrapping_sub(end)), None)
+            }
+            Value::Expression(text) => (f(0), Some((text, Relocation::Relative16))),
+        }
+    }
+
+    /// Like `relative`, for signed byte offsets.
+    fn short<'a>(
+        value: Value<'a>,
+        address: u16,
+        f: impl Fn(i8) -> Instruction,
+    ) -> Result<(Instruction, Fixup<'a>), CompileError> {
+        match value {
+            Value::Literal(target) => {
+                let end = address.wrapping_add(Vec::from(f(0)).len() as u16);
+                let offset = short_offset(&format!("${target:04X}"), end, target)?;
+                Ok((f(offset), None))
+            }
+            Value::Expression(text) => Ok((f(0), Some((text, Relocation::Relative8)))),
+        }
+    }
+
+    let (mnemonic, cond, operands) = match split_condition(mnemonic) {
+        Some((prefix, None)) => {
+            let (cond, operands) = operands
+                .split_first()
+                .ok_or_else(|| CompileError::InvalidInstruction(mnemonic.to_string()))?;
+            (prefix, Some(parse_condition(cond)?), operands)
+        }
+        Some((prefix, Some(cond))) => (prefix, Some(cond), operands),
+        None => (mnemonic, None, operands),
+    };
+
+    if let ("SET" | "CLR", [flag]) = (mnemonic, operands) {
+        let flag = parse_flag(flag)?;
+        return Ok((
+            if mnemonic == "SET" {
+                Set(flag)
+            } else {
+                Clear(flag)
+            },
+            None,
+        ));
+    }
+
+    let operands = operands
+        .iter()
+        .map(|operand| parse_operand(operand))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(match (mnemonic, cond, operands.as_slice()) {
+        ("LDA", None, [Register(reg)]) => (LoadFrom(*reg), None),
+        ("LDA", None, [Address(v)]) => with(*v, LoadAddress),
+        ("LDA", None, [Indirect]) => (LoadIndirect, None),
+        ("LDA", None, [IndirectPostInc]) => (LoadIndirectPostInc, None),
+        ("LDA", None, [Offset(v)]) => with(*v, LoadOffset),
+        ("LDA", None, [StackOffset(v)]) => with(*v, LoadStackOffset),
+        ("LDAB", None, [Address(v)]) => with(*v, LoadByteAddress),
+        ("LDAB", None, [Indirect]) => (LoadByteIndirect, None),
+        ("LDAB", None, [IndirectPostInc]) => (LoadByteIndirectPostInc, None),
+        ("LDAB", None, [Offset(v)]) => with(*v, LoadByteOffset),
+        ("LDAB", None, [StackOffset(v)]) => with(*v, LoadByteStackOffset),
+        ("STA", None, [Register(reg)]) => (StoreTo(*reg), None),
+        ("STA", None, [Address(v)]) => with(*v, StoreAddress),
+        ("STA", None, [Indirect]) => (StoreIndirect, None),
+        ("STA", None, [IndirectPostInc]) => (StoreIndirectPostInc, None),
+        ("STA", None, [Offset(v)]) => with(*v, StoreOffset),
+        ("STA", None, [StackOffset(v)]) => with(*v, StoreStackOffset),
+        ("STAB", None, [Address(v)]) => with(*v, StoreByteAddress),
+        ("STAB", None, [Indirect]) => (StoreByteIndirect, None),
+        ("STAB", None, [IndirectPostInc]) => (StoreByteIndirectPostInc, None),
+        ("STAB", None, [Offset(v)]) => with(*v, StoreByteOffset),
+        ("STAB", None, [StackOffset(v)]) => with(*v, StoreByteStackOffset),
+        ("ZERO", None, [Register(reg)]) => (Zero(*reg), None),
+        ("LDI", None, [Register(reg), Immediate(v)]) => with(*v, |v| LoadImmediate(*reg, v)),
+
+        ("NOT", None, [Register(reg)]) => (Not(*reg), None),
+        ("INC", None, [Register(reg)]) => (Increment(*reg), None),
+        ("DEC", None, [Register(reg)]) => (Decrement(*reg), None),
+        ("SEXT", None, [Register(reg)]) => (SignExtendByte(*reg), None),
+        ("ZEXT", None, [Register(reg)]) => (ZeroExtendByte(*reg), None),
+        ("SWAP", None, [Register(reg)]) => (SwapBytes(*reg), None),
+        ("AND", None, [Register(reg)]) => (And(*reg), None),
+        ("OR", None, [Register(reg)]) => (Or(*reg), None),
+        ("XOR", None, [Register(reg)]) => (Xor(*reg), None),
+        ("SHL", None, [Register(reg)]) => (LeftShift(*reg), None),
+        ("SHR", None, [Register(reg)]) => (RightShift(*reg), None),
+        ("SHL", None, [Immediate(v)]) => byte(*v, LeftShiftImmediate)?,
+        ("SHR", None, [Immediate(v)]) => byte(*v, RightShiftImmediate)?,
+        ("ROL", None, [Immediate(v)]) => byte(*v, RotateLeftImmediate)?,
+        ("ROR", None, [Immediate(v)]) => byte(*v, RotateRightImmediate)?,
+        ("ADD", None, [Register(reg)]) => (Add(*reg), None),
+        ("SUB", None, [Register(reg)]) => (Subtract(*reg), None),
+        ("ADC", None, [Register(reg)]) => (AddWithCarry(*reg), None),
+        ("SBB", None, [Register(reg)]) => (SubtractWithBorrow(*reg), None),
+        ("ADDI", None, [Immediate(v)]) => with(*v, AddImmediate),
+        ("SUBI", None, [Immediate(v)]) => with(*v, SubtractImmediate),
+        ("ANDI", None, [Immediate(v)]) => with(*v, AndImmediate),
+        ("ORI", None, [Immediate(v)]) => with(*v, OrImmediate),
+        ("XORI", None, [Immediate(v)]) => with(*v, XorImmediate),
+        ("CMP", None, [Register(reg)]) => (CompareA(*reg), None),
+        ("CMPI", None, [Register(reg), Immediate(v)]) => with(*v, |v| CompareImmediate(*reg, v)),
+
+        ("JMP", None, [Immediate(v)]) => with(*v, Jump),
+        ("JMPX", None, [Immediate(v)]) => with(*v, JumpOffset),
+        ("JR", None, [Immediate(v)]) => relative(*v, address, JumpRelative),
+        ("J", Some(cond), [Immediate(v)]) => with(*v, |v| JumpIf(cond, v)),
+        ("JX", Some(cond), [Immediate(v)]) => with(*v, |v| JumpOffsetIf(cond, v)),
+        ("JR", Some(cond), [Immediate(v)]) => relative(*v, address, |v| JumpRelativeIf(cond, v)),
+        ("LOOP", None, [Immediate(v)]) => with(*v, Loop),
+        ("LOOPX", None, [Immediate(v)]) => with(*v, LoopOffset),
+        ("LOOPR", None, [Immediate(v)]) => relative(*v, address, LoopRelative),
+        ("DJNZ", None, [Immediate(v)]) => short(*v, address, LoopNotZero)?,
+        ("CALL", None, [Immediate(v)]) => with(*v, Call),
+        ("CALLX", None, [Immediate(v)]) => with(*v, CallOffset),
+        ("CALLR", None, [Immediate(v)]) => relative(*v, address, CallRelative),
+        ("CALL", Some(cond), [Immediate(v)]) => with(*v, |v| CallIf(cond, v)),
+
+        ("PUSH", None, []) => (Push, None),
+        ("POP", None, []) => (Pop, None),
+        ("PUSHPC", None, []) => (PushPC, None),
+        ("RET", None, []) => (Return, None),
+        ("RET", None, [Immediate(v)]) => byte(*v, ReturnDeallocate)?,
+        ("RET", Some(cond), []) => (ReturnIf(cond), None),
+        ("PUSHF", None, []) => (PushFlags, None),
+        ("ENTER", None, [Immediate(v)]) => with(*v, Enter),
+        ("LEAVE", None, []) => (Leave, None),
+        ("POPF", None, []) => (PopFlags, None),
+        ("PUSHA", None, []) => (PushAll, None),
+        ("POPA", None, []) => (PopAll, None),
+        ("INT", None, []) => (CallInterrupt, None),
+        ("INT", None, [Immediate(Value::Literal(number))]) if *number < 8 => {
+            (Int(*number as u8), None)
+        }
+        ("RETI", None, []) => (ReturnInterrupt, None),
+        ("IN", None, []) => (Input, None),
+        ("OUT", None, []) => (Output, None),
+        ("SETINT", None, [Immediate(v)]) => with(*v, SetInterrupt),
+        ("HALT", None, []) => (Set(flag::HALT), None),
+        ("NOP", None, []) => (Nop, None),
+        ("BRK", None, []) => (Brk, None),
+
+        _ => return Err(CompileError::InvalidInstruction(mnemonic.to_string())),
+    })
+}
+
+/// Build the instruction for a single statement, such as `LDI B, #$C000`, for [`Instruction`]'s `FromStr`.
+///
+/// The statement may have a comment but no label. It is assembled as if at address 0, so branch targets are
+/// best written relative to `$`, as in `JR $+8`. Operands must not refer to other symbols, since there are none to
+/// look up.
+pub(crate) fn parse_statement(line: &str) -> Result<Instruction, CompileError> {
+    let line = strip_comment(line).trim();
+    let (mnemonic, rest) = split_mnemonic(line);
+    let operands = split_operands(rest);
+    let (instruction, fixup) = match parse_instruction(&mnemonic.to_ascii_uppercase(), &operands, 0)
+    {
+        Err(CompileError::InvalidInstruction(_)) => {
+            return Err(CompileError::InvalidInstruction(line.to_string()));
+        }
+        result => result?,
+    };
+    let Some((text, relocation)) = fixup else {
+        return Ok(instruction);
+    };
+    let value = Expr::parse(text)?.evaluate(&|symbol| (symbol == HERE).then_some(0))?;
+    let mut bytes = Vec::from(instruction);
+    let length = bytes.len();
+    relocation.patch(
+        &mut bytes,
+        length - relocation.size(),
+        length as u16,
+        value,
+        text,
+    )?;
+    let (instruction, _) = Instruction::try_from_iter(&bytes).expect("patching keeps the opcode");
+    Ok(instruction)
+}
+
+/// Split a conditional mnemonic into its prefix and condition.
+///
+/// Returns `None` for the condition when the mnemonic takes the condition as its first operand.
+fn split_condition(mnemonic: &str) -> Option<(&'static str, Option<u8>)> {
+    for &(prefix, explicit) in CONDITIONAL {
+        if mnemonic == explicit {
+            return Some((prefix, None));
+        }
+        if let Some(cond) = mnemonic.strip_prefix(prefix).and_then(condition::from_name) {
+            return Some((prefix, Some(cond)));
+        }
+    }
+    None
+}
+
+/// Parse a condition given by name (`NZ`) or by number (`8`).
+fn parse_condition(operand: &str) -> Result<u8, CompileError> {
+    condition::from_name(operand)
+        .or_else(|| {
+            parse_number(operand)
+                .filter(|&cond| cond < 16)
+                .map(|cond| cond as u8)
+        })
+        .ok_or_else(|| CompileError::InvalidCondition(operand.to_string()))
+}
+
+/// Parse a flag given by name (`HALT`, `H`) or by number (`15`).
+fn parse_flag(operand: &str) -> Result<u8, CompileError> {
+    let flag = match operand.to_ascii_uppercase().as_str() {
+        "Z" | "ZERO" => Some(flag::ZERO),
+        "S" | "SIGN" => Some(flag::SIGN),
+        "C" | "CARRY" => Some(flag::CARRY),
+        "O" | "OVERFLOW" => Some(flag::OVERFLOW),
+        "U" | "USER" => Some(flag::USER),
+        "BRK" | "BREAK" => Some(flag::BREAK),
+        "I" | "INTERRUPT" => Some(flag::INTERRUPT),
+        "H" | "HALT" => Some(flag::HALT),
+        _ => parse_number(operand)
+            .filter(|&flag| flag < 16)
+            .map(|flag| flag as u8),
+    };
+    flag.ok_or_else(|| CompileError::InvalidFlag(operand.to_string()))
+}
+
+fn parse_operand(operand: &str) -> Result<Operand<'_>, CompileError> {
+    if let Some(inner) = operand
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+    {
+        let inner = inner.trim();
+        if inner.eq_ignore_ascii_case("B") {
+            return Ok(Operand::Indirect);
+        }
+        if inner.eq_ignore_ascii_case("B+") {
+            return Ok(Operand::IndirectPostInc);
+        }
+        if let Some((base, offset)) = inner.split_once('+') {
+            match base.trim().to_ascii_uppercase().as_str() {
+                "B" => return Ok(Operand::Offset(parse_value(offset.trim())?)),
+                "SP" => return Ok(Operand::StackOffset(parse_value(offset.trim())?)),
+                _ => {}
+            }
+        }
+        return Ok(Operand::Address(parse_value(inner)?));
+    }
+    if let Some(value) = operand.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_value(value.trim())?));
+    }
+    match operand.to_ascii_uppercase().as_str() {
+        "A" => Ok(Operand::Register(GeneralPurposeRegister::A)),
+        "B" => Ok(Operand::Register(GeneralPurposeRegister::B)),
+        "C" => Ok(Operand::Register(GeneralPurposeRegister::C)),
+        "D" => Ok(Operand::Register(GeneralPurposeRegister::D)),
+        _ => Ok(Operand::Immediate(parse_value(operand)?)),
+    }
+}
+
+/// Parse a number or an expression. Expressions that do not refer to any symbol are folded to a literal.
+fn parse_value(value: &str) -> Result<Value<'_>, CompileError> {
+    if let Some(number) = parse_number(value) {
+        return Ok(Value::Literal(number));
+    }
+    let expr = Expr::parse(value)?;
+    let mut symbols = Vec::new();
+    expr.symbols(&mut symbols);
+    if symbols.is_empty() {
+        Ok(Value::Literal(expr.evaluate(&|_| None)?))
+    } else {
+        Ok(Value::Expression(value))
+    }
+}
+
+/// Parse a decimal (`-10`, `42`), hexadecimal (`$2A`, `0x2A`) or binary (`%101`, `0b101`) number.
+pub fn parse_number(number: &str) -> Option<u16> {
+    let (negative, number) = match number.strip_prefix('-') {
+        Some(number) => (true, number),
+        None => (false, number),
+    };
+    let (digits, radix) = if let Some(hex) = number
+        .strip_prefix('$')
+        .or_else(|| number.strip_prefix("0x"))
+    {
+        (hex, 16)
+    } else if let Some(bin) = number
+        .strip_prefix('%')
+        .or_else(|| number.strip_prefix("0b"))
+    {
+        (bin, 2)
+    } else {
+        (number, 10)
+    };
+    // `from_str_radix` takes a leading `+`, which would read `$+4` as the number 4 rather than the current address
+    // plus 4.
+    if !digits.starts_with(|c: char| c.is_digit(radix)) {
+        return None;
+    }
+    let value = u32::from_str_radix(digits, radix).ok()?;
+    match negative {
+        false if value <= u16::MAX as u32 => Some(value as u16),
+        true if value <= 0x8000 => Some((value as u16).wrapping_neg()),
+        _ => None,
+    }
+}
+
+/// Whether `name` is a valid symbol name. See [`compile`] for the grammar.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Whether `name` would be read as a register rather than a symbol.
+fn is_reserved(name: &str) -> bool {
+    ["A", "B", "C", "D", "SP"]
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(name))
+}
+
+/// The full name of the symbol `name` as written under the global label `scope`: local labels, which start with `.`,
+/// are prefixed with the label they follow, so `.loop` after `print:` is `print.loop`.
+fn qualify(scope: &str, name: &str) -> String {
+    if name.starts_with('.') {
+        format!("{scope}{name}")
+    } else {
+        name.to_string()
+    }
+}
+
+/// Remove a `;` comment, ignoring semicolons inside string literals.
+fn strip_comment(line: &str) -> &str {
+    &line[..comment_start(line, &[';']).unwrap_or(line.len())]
+}
+
+/// Index of the first of `markers` outside a string, where a comment starts.
+fn comment_start(line: &str, markers: &[char]) -> Option<usize> {
+    let mut in_string = false;
+    let mut chars = line.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => in_string = !in_string,
+            '\\' if in_string => {
+                chars.next();
+            }
+            c if !in_string && markers.contains(&c) => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Rewrite `source` from the dialect `options` describe into the default one. Every line stays on the same line, and
+/// code stays in the same columns.
+fn normalize<'s>(source: &'s str, options: &AssemblerOptions) -> Cow<'s, str> {
+    if options.comment_chars.is_empty() && !options.labels_without_colons {
+        return Cow::Borrowed(source);
+    }
+    let mut output = String::with_capacity(source.len());
+    for line in source.lines() {
+        let mut line = line.to_string();
+        if let Some(index) = comment_start(&line, &options.comment_chars) {
+            line.truncate(index);
+        }
+        let end = line.find(char::is_whitespace).unwrap_or(line.len());
+        if options.labels_without_colons && !line.starts_with('.') && is_identifier(&line[..end]) {
+            // Put the colon in place of the space after the label, so nothing after it moves.
+            match line[end..].chars().next() {
+                Some(space) => line.replace_range(end..end + space.len_utf8(), ":"),
+                None => line.push(':'),
+            }
+        }
+        output.push_str(&line);
+        output.push('\n');
+    }
+    Cow::Owned(output)
+}
+
+/// Parse a double-quoted string literal into bytes.
+///
+/// Escapes are `\n`, `\r`, `\t`, `\0`, `\\`, `\"` and `\xHH` for any byte.
+fn parse_string(text: &str) -> Result<Vec<u8>, CompileError> {
+    let invalid = || CompileError::InvalidString(text.to_string());
+    let string = text
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    let mut bytes = Vec::with_capacity(string.len());
+    let mut rest = string;
+    while let Some(index) = rest.find(['\\', '"']) {
+        bytes.extend_from_slice(&rest.as_bytes()[..index]);
+        // An unescaped quote ends the string early, leaving text after it.
+        let escape = rest[index..].strip_prefix('\\').ok_or_else(invalid)?;
+        let (byte, length) = match escape.as_bytes().first().ok_or_else(invalid)? {
+            b'n' => (b'\n', 1),
+            b'r' => (b'\r', 1),
+            b't' => (b'\t', 1),
+            b'0' => (0, 1),
+            b'\\' => (b'\\', 1),
+            b'"' => (b'"', 1),
+            b'x' => {
+                let hex = escape.get(1..3).ok_or_else(invalid)?;
+                (u8::from_str_radix(hex, 16).map_err(|_| invalid())?, 3)
+            }
+            _ => return Err(invalid()),
+        };
+        bytes.push(byte);
+        rest = &escape[length..];
+    }
+    bytes.extend_from_slice(rest.as_bytes());
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assemble `line` at address 0, followed by `.equ target` with `value`, so `target` is patched in through a
+    /// relocation. Returns the bytes of `line`, or the first error.
+    fn patched(line: &str, value: u16) -> Result<Vec<u8>, CompileError> {
+        compile(&format!("    {line}\n    .equ target {value}\n"))
+            .map(|(image, _)| image)
+            .map_err(|errors| errors[0].kind.clone())
+    }
+
+    #[test]
+    fn absolute8_takes_unsigned_and_sign_extended_bytes() {
+        assert_eq!(patched(".db target", 0x0000), Ok(vec![0x00]));
+        assert_eq!(patched(".db target", 0x00FF), Ok(vec![0xFF]));
+        assert_eq!(patched(".db target", 0xFF80), Ok(vec![0x80]));
+        assert_eq!(patched(".db target", 0xFFFF), Ok(vec![0xFF]));
+    }
+
+    #[test]
+    fn absolute8_rejects_values_between() {
+        let error = |value| Err(CompileError::OutOfRange("target".to_string(), value));
+        assert_eq!(patched(".db target", 0x0100), error(0x0100));
+        assert_eq!(patched(".db target", 0xFF7F), error(0xFF7F));
+    }
+
+    #[test]
+    fn absolute16_takes_any_word() {
+        assert_eq!(patched("JMP target", 0x0000), Ok(vec![0x60, 0x00, 0x00]));
+        assert_eq!(patched("JMP target", 0xFFFF), Ok(vec![0x60, 0xFF, 0xFF]));
+    }
+
+    #[test]
+    fn relative16_wraps_around_the_address_space() {
+        // `JR` is 3 bytes long, so offsets are measured from address 3.
+        assert_eq!(patched("JR target", 0x0003), Ok(vec![0x62, 0x00, 0x00]));
+        assert_eq!(patched("JR target", 0x0002), Ok(vec![0x62, 0xFF, 0xFF]));
+        assert_eq!(patched("JR target", 0x8002), Ok(vec![0x62, 0xFF, 0x7F]));
+        assert_eq!(patched("JR target", 0x8003), Ok(vec![0x62, 0x00, 0x80]));
+    }
+
+    #[test]
+    fn relative8_reaches_from_minus_128_to_127() {
+        // `DJNZ` is 2 bytes long, so offsets are measured from address 2.
+        assert_eq!(patched("DJNZ target", 2 + 127), Ok(vec![0x67, 0x7F]));
+        assert_eq!(
+            patched("DJNZ target", 2u16.wrapping_sub(128)),
+            Ok(vec![0x67, 0x80])
+        );
+    }
+
+    #[test]
+    fn relative8_rejects_targets_out_of_reach() {
+        let error = |distance| {
+            Err(CompileError::BranchOutOfRange(
+                "target".to_string(),
+                distance,
+            ))
+        };
+        assert_eq!(patched("DJNZ target", 2 + 128), error(128));
+        assert_eq!(patched("DJNZ target", 2u16.wrapping_sub(129)), error(-129));
+    }
+
+    #[test]
+    fn literal_targets_match_symbolic_ones() {
+        let source =
+            "    .def five 5\n    .org $10\n    JR 5\n    JR five\n    JR $+5\n    DJNZ $\n";
+        let (image, _) = compile(source).unwrap();
+        assert_eq!(
+            image[0x10..],
+            [
+                0x62, 0xF2, 0xFF, 0x62, 0xEF, 0xFF, 0x62, 0x02, 0x00, 0x67, 0xFE
+            ]
+        );
+    }
+
+    /// Assemble `source`, which must fail, and return the first error with its line.
+    fn first_error(source: &str) -> (Option<usize>, CompileError) {
+        let errors = compile(source).unwrap_err();
+        (errors[0].line, errors[0].kind.clone())
+    }
+
+    #[test]
+    fn macros_substitute_their_arguments() {
+        let source = "\
+    .macro pair x, y
+    LDI A, \\x
+    LDI B, \\y
+    .endmacro
+    PAIR 1, $1234
+    .macro spin
+    loop_\\@: JMP loop_\\@
+    .endmacro
+    spin
+    spin
+";
+        let (image, _) = compile(source).unwrap();
+        assert_eq!(
+            image,
+            [
+                0x0C, 0x01, 0x00, 0x0D, 0x34, 0x12, 0x60, 0x06, 0x00, 0x60, 0x09, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn macros_report_errors_on_the_invoking_line() {
+        assert_eq!(
+            first_error("    .macro pair x, y\n    NOP\n    .endmacro\n    pair 1\n"),
+            (
+                Some(4),
+                CompileError::InvalidInstruction("pair 1".to_string())
+            )
+        );
+        assert_eq!(
+            first_error("    .macro again\n    again\n    .endmacro\n    again\n"),
+            (Some(4), CompileError::MacroRecursion("again".to_string()))
+        );
+        assert_eq!(
+            first_error("    NOP\n    .macro open\n    NOP\n"),
+            (Some(2), CompileError::UnterminatedMacro("open".to_string()))
+        );
+        assert_eq!(
+            first_error("    .macro bad 1x\n    .endmacro\n"),
+            (Some(1), CompileError::InvalidOperand("1x".to_string()))
+        );
+    }
+
+    #[test]
+    fn incbin_includes_a_slice_of_a_file() {
+        let path = std::env::temp_dir().join(format!("incbin-{}.bin", std::process::id()));
+        std::fs::write(&path, [1, 2, 3, 4, 5]).unwrap();
+        let path = path.to_str().unwrap();
+        let include = |range: &str| compile(&format!("    .incbin \"{path}\"{range}\n"));
+        assert_eq!(include("").unwrap().0, [1, 2, 3, 4, 5]);
+        assert_eq!(include(", 3").unwrap().0, [4, 5]);
+        assert_eq!(include(", 1, 2").unwrap().0, [2, 3]);
+        assert_eq!(
+            include(", 4, 2").unwrap_err()[0].kind,
+            CompileError::InvalidOperand(format!("\"{path}\", 4, 2"))
+        );
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(
+            include("").unwrap_err()[0].kind,
+            CompileError::IncludeFailed(ref failed, _) if failed == path
+        ));
+    }
+
+    #[test]
+    fn expressions_follow_precedence_and_wrap() {
+        let source = "\
+    .dw 1 + 2 * 3, (1 + 2) * 3, 1 << 4 | 3, 6 ^ 3 & 1
+    .dw 7 / 2, 7 % 2, $8000 >> 15, -1 * 2
+    .dw ~0 & $F0, 2 - 3, end - start
+start:
+    .db 0
+end:
+";
+        let (image, _) = compile(source).unwrap();
+        let words: Vec<u16> = image[..22]
+            .chunks(2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]))
+            .collect();
+        assert_eq!(words, [7, 9, 0x13, 7, 3, 1, 1, 0xFFFE, 0xF0, 0xFFFF, 1]);
+    }
+
+    #[test]
+    fn expression_errors_name_the_operand() {
+        let error = |expression: &str| first_error(&format!("    .dw {expression}\n")).1;
+        assert_eq!(error("1 / 0"), CompileError::DivisionByZero);
+        assert_eq!(error("1 % (2 - 2)"), CompileError::DivisionByZero);
+        assert_eq!(
+            error("1 +"),
+            CompileError::InvalidOperand("1 +".to_string())
+        );
+        assert_eq!(
+            error("(1 + 2"),
+            CompileError::InvalidOperand("(1 + 2".to_string())
+        );
+        assert_eq!(
+            error("nowhere + 1"),
+            CompileError::UndefinedSymbol("nowhere".to_string())
+        );
+    }
+
+    #[test]
+    fn local_labels_belong_to_the_global_label_before_them() {
+        let source = "\
+first:
+    JMP .loop
+.loop:
+    JMP .loop
+second:
+.loop:
+    JMP .loop
+    JMP first.loop
+";
+        let (image, symbols, _) =
+            compile_with_symbols(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            image,
+            [
+                0x60, 0x03, 0x00, 0x60, 0x03, 0x00, 0x60, 0x06, 0x00, 0x60, 0x03, 0x00
+            ]
+        );
+        assert!(symbols.contains(&("first.loop".to_string(), 3)));
+        assert!(symbols.contains(&("second.loop".to_string(), 6)));
+        assert_eq!(
+            first_error("first:\n.loop:\n    NOP\n.loop:\n"),
+            (
+                Some(4),
+                CompileError::DuplicateLabel(".loop".to_string(), 2)
+            )
+        );
+    }
+
+    #[test]
+    fn rept_repeats_its_block_with_a_counter() {
+        let source = "\
+    .rept 3, i
+    .db i * 2
+    .rept 2
+    NOP
+    .endr
+    .endr
+    .rept 0
+    HALT
+    .endr
+";
+        let (image, _) = compile(source).unwrap();
+        assert_eq!(
+            image,
+            [0x00, 0xD3, 0xD3, 0x02, 0xD3, 0xD3, 0x04, 0xD3, 0xD3]
+        );
+        assert_eq!(
+            first_error("    NOP\n    .rept 2\n    NOP\n"),
+            (Some(2), CompileError::UnterminatedRepeat)
+        );
+        assert_eq!(
+            first_error("    .rept count\n    .endr\n"),
+            (Some(1), CompileError::InvalidOperand("count".to_string()))
+        );
+    }
+
+    #[test]
+    fn lo_and_hi_split_words_into_bytes() {
+        let source = "\
+    .db lo(handler), hi(handler), <$1234, >$1234, >(handler + $100)
+    .org $ABCD
+handler:
+    RET
+";
+        let (image, warnings) = compile(source).unwrap();
+        assert_eq!(image[..5], [0xCD, 0xAB, 0x34, 0x12, 0xAC]);
+        assert!(
+            !warnings
+                .iter()
+                .any(|warning| matches!(warning.kind, CompileWarning::Truncated(..)))
+        );
+        assert_eq!(
+            first_error("    .db hi()\n").1,
+            CompileError::InvalidOperand("hi()".to_string())
+        );
+    }
+
+    #[test]
+    fn listings_show_the_bytes_of_each_line() {
+        let source =
+            "start:\n    LDI B, $1234\n    .db 1, 2, 3, 4, 5\n    .rept 2\n    NOP\n    .endr\n";
+        let listing = compile_listing(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(
+            listing,
+            "    1                     start:
+    2  0000  0D 34 12         LDI B, $1234
+    3  0003  01 02 03 04      .db 1, 2, 3, 4, 5
+       0007  05
+    4                         .rept 2
+    5  0008  D3 D3            NOP
+    6                         .endr
+"
+        );
+    }
+
+    #[test]
+    fn sections_interleave_and_resume_where_they_left_off() {
+        let source = "    NOP\n    .section .data, $10\n    .db 1, 2\n    .section .text\n    NOP\n    .section .data\n    .db 3\n";
+        let artifact = assemble(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(artifact.image.len(), 0x13);
+        let [text, data] = artifact.sections.as_slice() else {
+            panic!("{:?}", artifact.sections);
+        };
+        assert_eq!(
+            (text.name.as_str(), text.start, text.end, text.size),
+            (".text", 0, 2, 2)
+        );
+        assert_eq!(
+            (data.name.as_str(), data.start, data.end, data.size),
+            (".data", 0x10, 0x13, 3)
+        );
+        assert_eq!(artifact.data(text), [0xD3, 0xD3]);
+        assert_eq!(artifact.data(data), [1, 2, 3]);
+    }
+
+    #[test]
+    fn bss_reserves_space_without_image_bytes() {
+        let source = "    NOP\n    .section .bss, $100\nbuffer:\n    .space 16\n    .section .text\n    NOP\n";
+        let artifact = assemble(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(artifact.image, [0xD3, 0xD3]);
+        let bss = &artifact.sections[1];
+        assert!(bss.uninitialized);
+        assert_eq!((bss.start, bss.end, bss.size), (0x100, 0x110, 16));
+        assert_eq!(artifact.data(bss), []);
+        assert!(artifact.symbols.contains(&("buffer".to_string(), 0x100)));
+        assert_eq!(
+            first_error("    .section .bss, $100\n    .db 1\n"),
+            (Some(2), CompileError::InitializedData(".bss".to_string()))
+        );
+    }
+
+    #[test]
+    fn section_end_is_the_highest_address_reached() {
+        let artifact = assemble(
+            "    .org $20\n    NOP\n    .org $10\n    NOP\n",
+            &AssemblerOptions::default(),
+        )
+        .unwrap();
+        let text = &artifact.sections[0];
+        assert_eq!((text.start, text.end, text.size), (0, 0x21, 2));
+        assert_eq!(artifact.data(text).len(), 0x21);
+        let artifact =
+            assemble("    .org $FFFE\n    .dw 0\n", &AssemblerOptions::default()).unwrap();
+        assert_eq!(artifact.sections[0].end, 0x10000);
+        assert_eq!(artifact.data(&artifact.sections[0]).len(), 0x10000);
+    }
+
+    #[test]
+    fn sections_place_code_in_rom_and_reservations_in_ram() {
+        let options = AssemblerOptions {
+            origin: 0x8000,
+            ..AssemblerOptions::default()
+        };
+        let source = "    .section .bss, $0200\ncounter:\n    .space 2\n    .section .text\nstart:\n    LDI A, counter\n";
+        let artifact = assemble(source, &options).unwrap();
+        assert_eq!(artifact.image, [0x0C, 0x00, 0x02]);
+        assert!(artifact.symbols.contains(&("start".to_string(), 0x8000)));
+        let [text, bss] = artifact.sections.as_slice() else {
+            panic!("{:?}", artifact.sections);
+        };
+        assert_eq!((text.start, text.end), (0x8000, 0x8003));
+        assert_eq!((bss.start, bss.end), (0x0200, 0x0202));
+    }
+
+    #[test]
+    fn origin_places_the_image_and_its_labels() {
+        let options = AssemblerOptions {
+            origin: 0x8000,
+            ..AssemblerOptions::default()
+        };
+        let artifact =
+            assemble("start:\n    JMP start\n    .org $8010\n    NOP\n", &options).unwrap();
+        assert_eq!(artifact.origin, 0x8000);
+        assert_eq!(artifact.image.len(), 0x11);
+        assert_eq!(artifact.image[..3], [0x60, 0x00, 0x80]);
+        assert_eq!(artifact.image[0x10], 0xD3);
+        assert!(artifact.symbols.contains(&("start".to_string(), 0x8000)));
+    }
+
+    #[test]
+    fn output_below_the_origin_is_an_error() {
+        let options = AssemblerOptions {
+            origin: 0x8000,
+            ..AssemblerOptions::default()
+        };
+        let errors = assemble("    NOP\n    .org $7FFF\n    NOP\n", &options).unwrap_err();
+        assert_eq!(errors[0].line, Some(3));
+        assert_eq!(errors[0].kind, CompileError::BelowOrigin(0x7FFF, 0x8000));
+        // A label below the origin is fine, as it adds nothing to the image.
+        let artifact = assemble(
+            "    .org $0200\nbuffer:\n    .org $8000\n    NOP\n",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(artifact.image, [0xD3]);
+        assert!(artifact.symbols.contains(&("buffer".to_string(), 0x0200)));
+    }
+
+    #[test]
+    fn redefinitions_are_reported_by_kind() {
+        let (line, error) = first_error("    .equ size 4\nsize:\n    NOP\n");
+        assert_eq!(
+            (line, &error),
+            (
+                Some(2),
+                &CompileError::LabelRedefinition("size".to_string())
+            )
+        );
+        assert_eq!(
+            error.to_string(),
+            "label `size` is already defined by .def or .equ"
+        );
+        let (line, error) = first_error("    .equ size 4\n    .equ size 5\n");
+        assert_eq!(
+            (line, &error),
+            (Some(2), &CompileError::Redefinition("size".to_string()))
+        );
+        assert_eq!(
+            error.to_string(),
+            "`size` is already defined; use .redef to change it"
+        );
+        // The same value again is allowed, and `.set` changes it.
+        assert!(compile("    .equ size 4\n    .equ size 4\n    .set size 5\n").is_ok());
+    }
+
+    #[test]
+    fn equates_resolve_through_later_equates() {
+        let source = "    .dw total\n    .equ total base + offset\n    .equ base $1000\n    .equ offset base / 2\n";
+        let artifact = assemble(source, &AssemblerOptions::default()).unwrap();
+        assert_eq!(artifact.image, [0x00, 0x18]);
+        assert!(artifact.symbols.contains(&("total".to_string(), 0x1800)));
+        assert_eq!(
+            first_error("    .equ x y + 1\n    .equ y x + 1\n"),
+            (Some(1), CompileError::UndefinedSymbol("y".to_string()))
+        );
+        assert_eq!(
+            first_error("    .equ x nowhere\n"),
+            (
+                Some(1),
+                CompileError::UndefinedSymbol("nowhere".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn labels_without_colons_need_indented_instructions() {
+        let options = AssemblerOptions {
+            labels_without_colons: true,
+            ..AssemblerOptions::default()
+        };
+        let source = "start\n    NOP\n.loop:\n    DJNZ .loop\nfinish  JMP start\nlast: NOP\n";
+        let artifact = assemble(source, &options).unwrap();
+        assert_eq!(artifact.image, [0xD3, 0x67, 0xFE, 0x60, 0x00, 0x00, 0xD3]);
+        assert!(artifact.symbols.contains(&("start".to_string(), 0)));
+        assert!(artifact.symbols.contains(&("finish".to_string(), 3)));
+        assert!(artifact.symbols.contains(&("last".to_string(), 6)));
+        // A mnemonic at the start of the line is taken as a label.
+        let artifact = assemble("NOP\n    JMP NOP\n", &options).unwrap();
+        assert_eq!(artifact.image, [0x60, 0x00, 0x00]);
+        assert_eq!(artifact.symbols, [("NOP".to_string(), 0)]);
+    }
+
+    #[test]
+    fn comment_chars_and_ignore_case_change_the_dialect() {
+        let options = AssemblerOptions {
+            ignore_case: true,
+            comment_chars: vec!['#'],
+            ..AssemblerOptions::default()
+        };
+        let source = "Start: NOP # comment\n    .ascii \"#\"\n    JMP START\n";
+        let artifact = assemble(source, &options).unwrap();
+        assert_eq!(artifact.image, [0xD3, b'#', 0x60, 0x00, 0x00]);
+        let errors = assemble(source, &AssemblerOptions::default()).unwrap_err();
+        assert_eq!(errors[0].line, Some(1));
+        assert_eq!(
+            errors[1].kind,
+            CompileError::UndefinedSymbol("START".to_string())
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Constant expressions in operands, such as `table_end-table_start` or `(1 << 4) | flags`.
+//!
+//! Operators, from loosest to tightest binding: `|`, `^`, `&`, `<<` `>>`, `+` `-`, `*` `/` `%`, and the unary `-`,
+//! `~`, `<` (low byte) and `>` (high byte). Arithmetic wraps at 16 bits; `/`, `%` and `>>` are unsigned.
+//!
+//! `lo(x)` and `hi(x)` are the same as `<(x)` and `>(x)`, for building split tables such as `.db lo(handler)`.
+
+use super::{CompileError, HERE, is_identifier, parse_number};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(super) enum BinaryOp {
+    Or,
+    Xor,
+    And,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl BinaryOp {
+    /// Binding strength; higher binds tighter.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::Xor => 2,
+            BinaryOp::And => 3,
+            BinaryOp::ShiftLeft | BinaryOp::ShiftRight => 4,
+            BinaryOp::Add | BinaryOp::Subtract => 5,
+            BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Remainder => 6,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(super) enum Expr<'a> {
+    Literal(u16),
+    Symbol(&'a str),
+    Negate(Box<Expr<'a>>),
+    Not(Box<Expr<'a>>),
+    Binary(BinaryOp, Box<Expr<'a>>, Box<Expr<'a>>),
+}
+
+impl<'a> Expr<'a> {
+    pub(super) fn parse(text: &'a str) -> Result<Self, CompileError> {
+        let mut parser = Parser { text, position: 0 };
+        let expr = parser.binary(0)?;
+        parser.skip_whitespace();
+        if parser.position != text.len() {
+            return Err(CompileError::InvalidOperand(text.to_string()));
+        }
+        Ok(expr)
+    }
+
+    /// Evaluate the expression, looking symbols up with `lookup`.
+    pub(super) fn evaluate(
+        &self,
+        lookup: &impl Fn(&str) -> Option<u16>,
+    ) -> Result<u16, CompileError> {
+        Ok(match self {
+            Expr::Literal(value) => *value,
+            Expr::Symbol(symbol) => {
+                lookup(symbol).ok_or_else(|| CompileError::UndefinedSymbol(symbol.to_string()))?
+            }
+            Expr::Negate(expr) => expr.evaluate(lookup)?.wrapping_neg(),
+            Expr::Not(expr) => !expr.evaluate(lookup)?,
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.evaluate(lookup)?, right.evaluate(lookup)?);
+                match op {
+                    BinaryOp::Or => left | right,
+                    BinaryOp::Xor => left ^ right,
+                    BinaryOp::And => left & right,
+                    BinaryOp::ShiftLeft => left.checked_shl(right as u32).unwrap_or(0),
+                    BinaryOp::ShiftRight => left.checked_shr(right as u32).unwrap_or(0),
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Subtract => left.wrapping_sub(right),
+                    BinaryOp::Multiply => left.wrapping_mul(right),
+                    BinaryOp::Divide => left
+                        .checked_div(right)
+                        .ok_or(CompileError::DivisionByZero)?,
+                    BinaryOp::Remainder => left
+                        .checked_rem(right)
+                        .ok_or(CompileError::DivisionByZero)?,
+                }
+            }
+        })
+    }
+
+    /// Every symbol the expression refers to.
+    pub(super) fn symbols(&self, symbols: &mut Vec<&'a str>) {
+        match self {
+            Expr::Literal(_) => {}
+            Expr::Symbol(symbol) => symbols.push(*symbol),
+            Expr::Negate(expr) | Expr::Not(expr) => expr.symbols(symbols),
+            Expr::Binary(_, left, right) => {
+                left.symbols(symbols);
+                right.symbols(symbols);
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn error(&self) -> CompileError {
+        CompileError::InvalidOperand(self.text.to_string())
+    }
+
+    /// Parse operators binding at least as tightly as `min_precedence`.
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr<'a>, CompileError> {
+        let mut left = self.unary()?;
+        loop {
+            self.skip_whitespace();
+            let (op, length) = match self.rest().as_bytes() {
+                [b'<', b'<', ..] => (BinaryOp::ShiftLeft, 2),
+                [b'>', b'>', ..] => (BinaryOp::ShiftRight, 2),
+                [b'|', ..] => (BinaryOp::Or, 1),
+                [b'^', ..] => (BinaryOp::Xor, 1),
+                [b'&', ..] => (BinaryOp::And, 1),
+                [b'+', ..] => (BinaryOp::Add, 1),
+                [b'-', ..] => (BinaryOp::Subtract, 1),
+                [b'*', ..] => (BinaryOp::Multiply, 1),
+                [b'/', ..] => (BinaryOp::Divide, 1),
+                [b'%', ..] => (BinaryOp::Remainder, 1),
+                _ => return Ok(left),
+            };
+            if op.precedence() < min_precedence {
+                return Ok(left);
+            }
+            self.position += length;
+            let right = self.binary(op.precedence() + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr<'a>, CompileError> {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let Some(first) = rest.chars().next() else {
+            return Err(self.error());
+        };
+        match first {
+            '-' => {
+                self.position += 1;
+                Ok(Expr::Negate(Box::new(self.unary()?)))
+            }
+            '~' => {
+                self.position += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            '+' => {
+                self.position += 1;
+                self.unary()
+            }
+            '<' => {
+                self.position += 1;
+                Ok(low_byte(self.unary()?))
+            }
+            '>' => {
+                self.position += 1;
+                Ok(high_byte(self.unary()?))
+            }
+            '(' => {
+                self.position += 1;
+                let expr = self.binary(0)?;
+                self.skip_whitespace();
+                if !self.rest().starts_with(')') {
+                    return Err(self.error());
+                }
+                self.position += 1;
+                Ok(expr)
+            }
+            // `$` and `*` on their own are the current address; `$` followed by digits is a hexadecimal number.
+            '*' => {
+                self.position += 1;
+                Ok(Expr::Symbol(HERE))
+            }
+            '$' if !rest[1..].starts_with(|c: char| c.is_ascii_hexdigit()) => {
+                self.position += 1;
+                Ok(Expr::Symbol(HERE))
+            }
+            '$' | '%' => {
+                let length = 1 + rest[1..]
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(rest.len() - 1);
+                self.literal(length)
+            }
+            _ => {
+                let length = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                    .unwrap_or(rest.len());
+                let token = &rest[..length];
+                let call = rest[length..].trim_start().starts_with('(');
+                if call && token.eq_ignore_ascii_case("lo") {
+                    self.position += length;
+                    Ok(low_byte(self.unary()?))
+                } else if call && token.eq_ignore_ascii_case("hi") {
+                    self.position += length;
+                    Ok(high_byte(self.unary()?))
+                } else if is_identifier(token) {
+                    self.position += length;
+                    Ok(Expr::Symbol(token))
+                } else {
+                    self.literal(length)
+                }
+            }
+        }
+    }
+
+    fn literal(&mut self, length: usize) -> Result<Expr<'a>, CompileError> {
+        let value = parse_number(&self.rest()[..length]).ok_or_else(|| self.error())?;
+        self.position += length;
+        Ok(Expr::Literal(value))
+    }
+}
+
+fn low_byte(expr: Expr<'_>) -> Expr<'_> {
+    Expr::Binary(BinaryOp::And, Box::new(expr), Box::new(Expr::Literal(0xFF)))
+}
+
+fn high_byte(expr: Expr<'_>) -> Expr<'_> {
+    Expr::Binary(
+        BinaryOp::ShiftRight,
+        Box::new(expr),
+        Box::new(Expr::Literal(8)),
+    )
+}
@@ -0,0 +1,199 @@
+//! Assemble and run every `.asm` file in a directory, each on its own emulator, and print a summary.
+//!
+//! ```text
+//! batch <dir> [--jobs N] [--steps N] [--watchdog N]
+//! ```
+//!
+//! Programs run with no input, and their console output is captured. The `stack` column is the deepest the stack
+//! got, in bytes. A program passes if it halts, including at a
+//! breakpoint, within the step limit (1000000 by default). The exit status is 1 if any program fails.
+//!
+//! With `--watchdog N`, a program that spends `N` instructions looping within 16 bytes of code without writing memory
+//! or using a port is stopped as stuck, and the loop is printed below its row. `--watchdog 0` turns it off.
+
+use asm::assemble::{START_ADDRESS, compile};
+use asm::emulator::{Emulator, MEM_SIZE, RunResult, STACK_TOP, StopReason, Watchdog};
+use asm::isa::Instruction;
+use asm::memory::{Memory, MemoryExt};
+use asm::port::{Ports, ScriptedDevice};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const DEFAULT_STEPS: u64 = 1_000_000;
+/// Bytes of code a loop may span for `--watchdog` to catch it.
+const WATCHDOG_WINDOW: u16 = 16;
+
+enum Outcome {
+    NotRun(String),
+    /// The result, the console output, and the instructions of the loop if the program got stuck.
+    Ran(RunResult, Vec<u8>, Vec<(u16, Instruction)>),
+}
+
+impl Outcome {
+    fn passed(&self) -> bool {
+        matches!(
+            self,
+            Outcome::Ran(
+                RunResult {
+                    reason: StopReason::Halt | StopReason::Break,
+                    ..
+                },
+                ..
+            )
+        )
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: batch <dir> [--jobs N] [--steps N] [--watchdog N]");
+    exit(2);
+}
+
+fn main() {
+    let mut dir = None;
+    let mut jobs = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut steps = DEFAULT_STEPS;
+    let mut watchdog = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--jobs" => {
+                jobs = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--steps" => {
+                steps = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--watchdog" => {
+                watchdog = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .map(|steps| Watchdog {
+                        window: WATCHDOG_WINDOW,
+                        steps,
+                    })
+                    .or_else(|| usage())
+            }
+            _ if dir.is_none() => dir = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(dir) = dir else { usage() };
+
+    let mut programs: Vec<PathBuf> = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+            .collect(),
+        Err(err) => {
+            eprintln!("{dir}: {err}");
+            exit(1);
+        }
+    };
+    programs.sort();
+
+    let next = AtomicUsize::new(0);
+    let outcomes: Vec<Mutex<Option<Outcome>>> = programs.iter().map(|_| Mutex::new(None)).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = programs.get(index) else {
+                        break;
+                    };
+                    *outcomes[index].lock().unwrap() = Some(run_program(path, steps, watchdog));
+                }
+            });
+        }
+    });
+
+    let width = programs
+        .iter()
+        .map(|path| path.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("program".len());
+    println!(
+        "{:width$}  {:<24}  {:>8}  {:>5}  output",
+        "program", "result", "steps", "stack"
+    );
+    let mut failed = 0;
+    for (path, outcome) in programs.iter().zip(outcomes) {
+        let outcome = outcome.into_inner().unwrap().unwrap();
+        if !outcome.passed() {
+            failed += 1;
+        }
+        let name = path.display();
+        match outcome {
+            Outcome::NotRun(err) => println!("{name:width$}  {err}"),
+            Outcome::Ran(result, output, stuck) => {
+                println!(
+                    "{name:width$}  {:<24}  {:>8}  {:>5}  {:?}",
+                    format!("{:?}", result.reason),
+                    result.steps,
+                    result.max_stack_depth,
+                    String::from_utf8_lossy(&output)
+                );
+                for (address, instruction) in stuck {
+                    println!("    {address:04X}: {instruction}");
+                }
+            }
+        }
+    }
+    println!("{} passed, {failed} failed", programs.len() - failed);
+    if failed > 0 {
+        exit(1);
+    }
+}
+
+fn run_program(path: &Path, steps: u64, watchdog: Option<Watchdog>) -> Outcome {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) => return Outcome::NotRun(err.to_string()),
+    };
+    let image = match compile(&source) {
+        Ok((image, _)) => image,
+        // Only the first error fits in the table.
+        Err(errors) => return Outcome::NotRun(errors[0].to_string()),
+    };
+
+    let console = Rc::new(RefCell::new(ScriptedDevice::new([])));
+    let ports = Ports::with_device(console.clone());
+    let mut emu = Emulator::with_ports(Box::new([0; MEM_SIZE]), STACK_TOP, ports);
+    emu.memory.write_array(START_ADDRESS as usize, &image);
+
+    let result = emu.run_watched(Some(steps), watchdog);
+    let output = console
+        .borrow()
+        .writes
+        .iter()
+        .map(|&(_, value)| value as u8)
+        .collect();
+    let mut stuck = Vec::new();
+    if let StopReason::Stuck { start, end } = result.reason {
+        // Count bytes rather than compare addresses, which wrap if the loop runs past the top of memory.
+        let span = end.wrapping_sub(start) as usize + 1;
+        let mut offset = 0;
+        while offset < span {
+            let address = start.wrapping_add(offset as u16);
+            let Ok((instruction, length)) =
+                Instruction::try_from_iter(emu.memory.read_array::<4>(address as usize).iter())
+            else {
+                break;
+            };
+            stuck.push((address, instruction));
+            offset += length as usize;
+        }
+    }
+    Outcome::Ran(result, output, stuck)
+}
@@ -0,0 +1,361 @@
+//! Assemble a source file into a memory image.
+//!
+//! ```text
+//! compile <input> <output> [OPTIONS]
+//! compile <input> --stdout [OPTIONS]
+//! ```
+//!
+//! Options: `-D NAME[=VALUE]`, `--format raw|hex|srec`, `--symbols FILE`, `--listing FILE`, `--elf FILE`,
+//! `--debug-info FILE`, `--max-size BYTES`, `--origin ADDRESS`, `--ignore-case`, `--comment CHAR` and
+//! `--labels-without-colons`.
+//!
+//! `-` reads the source from stdin or writes the image to stdout. `--stdout` prints a hexdump instead of the raw image.
+//! `-D` defines a symbol before the first line, as `.def` would, with the value 1 if none is given; `-DNAME` works too.
+//! `--format` writes the image as raw bytes, the default, as Intel HEX, or as Motorola S-records. `--symbols` writes
+//! the symbol table to `FILE`, one `ADDR name` line per symbol with the value in hex. `--listing` writes each source
+//! line to `FILE` alongside the address and bytes it assembled to. `--elf` writes the image and symbols to `FILE` as an
+//! ELF executable for use with other binary tools. `--max-size` fails the build if any section holds more than `BYTES`,
+//! or the image is longer, 32 KiB by default to fit a ROM bank, and reports the size of each. `--origin` places the
+//! image at `ADDRESS`, written like a number in source, instead of at `START_ADDRESS`. `--debug-info` writes a line
+//! table to `FILE`, for `asm --debug-info` to show the source line of each instruction as it runs.
+//!
+//! The last three port source from other assemblers: `--ignore-case` makes symbols case-insensitive, `--comment` adds
+//! `CHAR` as a comment character alongside `;`, and `--labels-without-colons` takes an identifier at the start of a
+//! line as a label.
+
+use asm::assemble::{Artifact, AssemblerOptions, assemble, parse_number};
+use asm::elf;
+use std::io::{Read, Write, stdin, stdout};
+use std::process::exit;
+
+/// Bytes of the image in each Intel HEX or S-record record.
+const RECORD_LENGTH: usize = 16;
+/// Largest a section may be without `--max-size`, the 32 KiB of a ROM bank.
+const DEFAULT_MAX_SIZE: usize = 0x8000;
+
+/// How the image is written to the output.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Format {
+    Raw,
+    IntelHex,
+    SRecord,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: compile <input|-> <output|-|--stdout> [-D NAME[=VALUE]] [--format raw|hex|srec] [--symbols FILE] [--listing FILE] [--elf FILE] [--debug-info FILE] [--max-size BYTES] [--origin ADDRESS] [--ignore-case] [--comment CHAR] [--labels-without-colons]"
+    );
+    exit(2);
+}
+
+fn main() {
+    let mut positional = Vec::new();
+    let mut symbols_path = None;
+    let mut listing_path = None;
+    let mut elf_path = None;
+    let mut debug_info_path = None;
+    let mut max_size = DEFAULT_MAX_SIZE;
+    let mut options = AssemblerOptions::default();
+    let mut format = Format::Raw;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => symbols_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--listing" => listing_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--elf" => elf_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--debug-info" => debug_info_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--max-size" => {
+                let size = args.next().and_then(|size| size.parse::<usize>().ok());
+                max_size = size.unwrap_or_else(|| usage());
+            }
+            "--origin" => {
+                let origin = args.next().and_then(|origin| parse_number(&origin));
+                options.origin = origin.unwrap_or_else(|| usage());
+            }
+            "--ignore-case" => options.ignore_case = true,
+            "--labels-without-colons" => options.labels_without_colons = true,
+            "--comment" => {
+                let marker = args.next().and_then(|marker| marker.parse::<char>().ok());
+                options
+                    .comment_chars
+                    .push(marker.unwrap_or_else(|| usage()));
+            }
+            "--format" => {
+                format = match args.next().as_deref() {
+                    Some("raw") => Format::Raw,
+                    Some("hex") => Format::IntelHex,
+                    Some("srec") => Format::SRecord,
+                    _ => usage(),
+                }
+            }
+            "-D" => {
+                let define = args.next().unwrap_or_else(|| usage());
+                options
+                    .defines
+                    .push(parse_define(&define).unwrap_or_else(|| usage()));
+            }
+            _ if arg.starts_with("-D") => {
+                options
+                    .defines
+                    .push(parse_define(&arg[2..]).unwrap_or_else(|| usage()));
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let [input, output] = positional.as_slice() else {
+        usage()
+    };
+    let (input, output) = (input.as_str(), output.as_str());
+
+    let mut source = String::new();
+    let read = if input == "-" {
+        stdin().read_to_string(&mut source).map(|_| ())
+    } else {
+        std::fs::read_to_string(input).map(|text| source = text)
+    };
+    if let Err(err) = read {
+        eprintln!("{input}: {err}");
+        exit(1);
+    }
+
+    let artifact = match assemble(&source, &options) {
+        Ok(artifact) => {
+            for warning in &artifact.warnings {
+                eprintln!("{input}:{warning}");
+            }
+            artifact
+        }
+        Err(errors) => {
+            for diagnostic in &errors {
+                match diagnostic.line {
+                    Some(_) => eprintln!("{input}:{diagnostic}"),
+                    None => eprintln!("{input}: {diagnostic}"),
+                }
+            }
+            eprintln!("{} error(s)", errors.len());
+            exit(1);
+        }
+    };
+
+    if let Err(report) = check_sizes(&artifact, options.origin, max_size) {
+        eprint!("{input}: {report}");
+        exit(1);
+    }
+
+    let written = match output {
+        "--stdout" => {
+            let mut out = stdout().lock();
+            artifact
+                .image
+                .chunks(16)
+                .enumerate()
+                .try_for_each(|(index, chunk)| {
+                    let address = options.origin.wrapping_add((index * 16) as u16);
+                    let hex: Vec<String> = chunk.iter().map(|byte| format!("{byte:02X}")).collect();
+                    writeln!(out, "{address:04X}: {}", hex.join(" "))
+                })
+        }
+        path => {
+            let data = match format {
+                Format::Raw => artifact.image.clone(),
+                Format::IntelHex => intel_hex(&artifact.image, options.origin).into_bytes(),
+                Format::SRecord => s_records(&artifact.image, options.origin).into_bytes(),
+            };
+            match path {
+                "-" => stdout().lock().write_all(&data),
+                path => std::fs::write(path, data),
+            }
+        }
+    };
+    if let Err(err) = written {
+        eprintln!("{output}: {err}");
+        exit(1);
+    }
+
+    if let Some(path) = symbols_path {
+        let table: String = artifact
+            .symbols
+            .iter()
+            .map(|(name, value)| format!("{value:04X} {name}\n"))
+            .collect();
+        if let Err(err) = std::fs::write(&path, table) {
+            eprintln!("{path}: {err}");
+            exit(1);
+        }
+    }
+
+    if let Some(path) = listing_path {
+        let listing = artifact.listing(&source);
+        if let Err(err) = std::fs::write(&path, listing) {
+            eprintln!("{path}: {err}");
+            exit(1);
+        }
+    }
+
+    if let Some(path) = elf_path {
+        let file = elf::write(
+            &artifact.image,
+            options.origin,
+            options.origin,
+            &artifact.symbols,
+        );
+        if let Err(err) = std::fs::write(&path, file) {
+            eprintln!("{path}: {err}");
+            exit(1);
+        }
+    }
+
+    if let Some(path) = debug_info_path {
+        let table = artifact.line_table(&source).to_bytes();
+        if let Err(err) = std::fs::write(&path, table) {
+            eprintln!("{path}: {err}");
+            exit(1);
+        }
+    }
+}
+
+/// Check every section, and the whole image with any gaps between them, against `max_size` bytes. If any is over,
+/// returns a report of the size of each.
+fn check_sizes(artifact: &Artifact, origin: u16, max_size: usize) -> Result<(), String> {
+    let image = artifact.image.len();
+    if image <= max_size
+        && artifact
+            .sections
+            .iter()
+            .all(|section| section.size <= max_size)
+    {
+        return Ok(());
+    }
+    let sections = artifact.sections.iter().map(|section| {
+        (
+            section.name.as_str(),
+            section.start,
+            (section.end as u16).wrapping_sub(1),
+            section.size,
+        )
+    });
+    let image_row = (
+        "image",
+        origin,
+        origin.wrapping_add(image as u16).wrapping_sub(1),
+        image,
+    );
+    let mut report = format!("output over the limit of {max_size} bytes\n");
+    for (name, first, last, size) in sections.chain([image_row]).filter(|&(.., size)| size > 0) {
+        let over = if size > max_size {
+            format!(", {} over", size - max_size)
+        } else {
+            String::new()
+        };
+        report.push_str(&format!(
+            "  {name:<12} ${first:04X}-${last:04X}  {size} bytes{over}\n"
+        ));
+    }
+    Err(report)
+}
+
+/// Parse `NAME=VALUE`, or `NAME` alone for the value 1.
+fn parse_define(define: &str) -> Option<(String, u16)> {
+    match define.split_once('=') {
+        Some((name, value)) => Some((name.to_string(), parse_number(value)?)),
+        None => Some((define.to_string(), 1)),
+    }
+}
+
+/// `image` at `origin` as Intel HEX data records and an end-of-file record.
+fn intel_hex(image: &[u8], origin: u16) -> String {
+    let mut hex = String::new();
+    for (index, chunk) in image.chunks(RECORD_LENGTH).enumerate() {
+        let address = origin.wrapping_add((index * RECORD_LENGTH) as u16);
+        let mut record = vec![chunk.len() as u8];
+        record.extend_from_slice(&address.to_be_bytes());
+        record.push(0x00);
+        record.extend_from_slice(chunk);
+        let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        record.push(sum.wrapping_neg());
+        hex.push_str(&format!(":{}\n", to_hex(&record)));
+    }
+    hex.push_str(":00000001FF\n");
+    hex
+}
+
+/// `image` at `origin` as Motorola S1 data records and an S9 record giving `origin` as the entry point.
+fn s_records(image: &[u8], origin: u16) -> String {
+    let mut records = String::new();
+    let mut push = |kind: &str, address: u16, data: &[u8]| {
+        // The count covers the address, the data and the checksum.
+        let mut record = vec![(data.len() + 3) as u8];
+        record.extend_from_slice(&address.to_be_bytes());
+        record.extend_from_slice(data);
+        let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        record.push(!sum);
+        records.push_str(&format!("{kind}{}\n", to_hex(&record)));
+    };
+    for (index, chunk) in image.chunks(RECORD_LENGTH).enumerate() {
+        push(
+            "S1",
+            origin.wrapping_add((index * RECORD_LENGTH) as u16),
+            chunk,
+        );
+    }
+    push("S9", origin, &[]);
+    records
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizes(source: &str, options: &AssemblerOptions) -> Result<(), String> {
+        let artifact = assemble(source, options).unwrap();
+        check_sizes(&artifact, options.origin, DEFAULT_MAX_SIZE)
+    }
+
+    #[test]
+    fn counts_emitted_bytes_not_org_gaps() {
+        let options = AssemblerOptions::default();
+        let artifact = assemble("NOP\n.org $FFF0\n.dw 0\n", &options).unwrap();
+        assert_eq!(artifact.sections[0].size, 3);
+        let report = check_sizes(&artifact, options.origin, DEFAULT_MAX_SIZE).unwrap_err();
+        assert!(
+            report.contains("  .text        $0000-$FFF1  3 bytes\n"),
+            "{report}"
+        );
+        assert!(
+            report.contains("  image        $0000-$FFF1  65522 bytes, 32754 over\n"),
+            "{report}"
+        );
+        assert!(sizes("NOP\n.org $10\n.dw 0\n", &options).is_ok());
+    }
+
+    #[test]
+    fn rejects_section_reaching_top_of_memory() {
+        let options = AssemblerOptions {
+            origin: 0x7000,
+            ..AssemblerOptions::default()
+        };
+        let report = sizes(".space $9000\n", &options).unwrap_err();
+        assert!(
+            report.contains(".text        $7000-$FFFF  36864 bytes, 4096 over"),
+            "{report}"
+        );
+    }
+
+    #[test]
+    fn rejects_image_longer_than_limit() {
+        let report = sizes(
+            "NOP\n.org $9000\nNOP\n.org $10\nNOP\n",
+            &AssemblerOptions::default(),
+        )
+        .unwrap_err();
+        assert!(
+            report.contains("image        $0000-$9000  36865 bytes, 4097 over"),
+            "{report}"
+        );
+        assert!(report.contains(".text"), "{report}");
+    }
+}
@@ -0,0 +1,96 @@
+//! Disassemble a raw image, printing the address, bytes and instruction on each line.
+//!
+//! ```text
+//! disasm <image|-> [--base ADDRESS] [--symbols FILE]
+//! ```
+//!
+//! `--base` is the address the image is loaded at, 0 by default. `--symbols` reads a symbol table written by
+//! `compile --symbols`: each symbol is printed as a label before the instruction at its address, and branch targets and
+//! memory addresses it names are written as the symbol. Bytes that do not decode are shown as `.db`.
+
+use asm::assemble::{START_ADDRESS, parse_number};
+use asm::disassemble::disassemble;
+use std::collections::HashMap;
+use std::io::Read;
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("usage: disasm <image|-> [--base ADDRESS] [--symbols FILE]");
+    exit(2);
+}
+
+fn main() {
+    let mut positional = Vec::new();
+    let mut base = START_ADDRESS;
+    let mut symbols_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => {
+                let address = args.next().and_then(|address| parse_number(&address));
+                base = address.unwrap_or_else(|| usage());
+            }
+            "--symbols" => symbols_path = Some(args.next().unwrap_or_else(|| usage())),
+            _ => positional.push(arg),
+        }
+    }
+    let [input] = positional.as_slice() else {
+        usage();
+    };
+
+    let image = if input == "-" {
+        let mut image = Vec::new();
+        std::io::stdin().read_to_end(&mut image).map(|_| image)
+    } else {
+        std::fs::read(input)
+    };
+    let image = image.unwrap_or_else(|err| {
+        eprintln!("{input}: {err}");
+        exit(1);
+    });
+
+    let mut symbols = HashMap::new();
+    if let Some(path) = symbols_path {
+        let table = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+            eprintln!("{path}: {err}");
+            exit(1);
+        });
+        for (number, line) in table.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Some((address, name)) = parse_symbol(line) else {
+                eprintln!("{path}:{}: expected `ADDRESS NAME`", number + 1);
+                exit(1);
+            };
+            // Keep the first name given to an address.
+            symbols.entry(address).or_insert(name);
+        }
+    }
+
+    for decoded in disassemble(&image, base) {
+        if let Some(name) = symbols.get(&decoded.address) {
+            println!("{name}:");
+        }
+        let bytes: Vec<String> = decoded
+            .bytes
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect();
+        println!(
+            "{:04X}  {:<11}  {}",
+            decoded.address,
+            bytes.join(" "),
+            decoded.text(&symbols)
+        );
+    }
+}
+
+/// Parse a line of a symbol table, `ADDRESS NAME` with the address in hexadecimal.
+fn parse_symbol(line: &str) -> Option<(u16, String)> {
+    let (address, name) = line.trim().split_once(' ')?;
+    Some((
+        u16::from_str_radix(address, 16).ok()?,
+        name.trim().to_string(),
+    ))
+}
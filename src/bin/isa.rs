@@ -0,0 +1,121 @@
+//! Print a JSON description of the instruction set, for external disassemblers and reverse-engineering tools.
+//!
+//! ```text
+//! isa
+//! ```
+//!
+//! The description is read off the decoder, so it always matches the emulator. Each instruction has its opcode, the
+//! name of its `Instruction` variant, its length in bytes and its operands. An operand is either fixed by the opcode,
+//! such as a register or the condition of `JumpIf`, or a little-endian field at `offset` bytes into the instruction,
+//! `size` bytes long.
+
+use asm::isa::Instruction;
+
+/// Operand bytes to decode every opcode with. Each field an instruction can have reads as a distinct value, which
+/// tells where the field is: a byte at offset 1 reads as `$05`, a word at offset 1 as `$3405`, and so on. The first
+/// byte is a valid condition, so conditional instructions decode too.
+const PROBE: [u8; 3] = [0x05, 0x34, 0x12];
+/// Different operand bytes, to tell fields fixed by the opcode, which read the same with both probes.
+const SECOND_PROBE: [u8; 3] = [0x06, 0x35, 0x13];
+
+fn main() {
+    let instructions: Vec<String> = (0..=u8::MAX)
+        .filter_map(describe)
+        .map(|instruction| format!("    {instruction}"))
+        .collect();
+
+    println!("{{");
+    println!("  \"endianness\": \"little\",");
+    println!("  \"address_size\": 16,");
+    println!("  \"registers\": [\"A\", \"B\", \"C\", \"D\", \"PC\", \"SP\", \"FLAGS\"],");
+    println!("  \"instructions\": [");
+    println!("{}", instructions.join(",\n"));
+    println!("  ]");
+    println!("}}");
+}
+
+/// The JSON description of the instruction with `opcode`, or `None` if it is not valid.
+fn describe(opcode: u8) -> Option<String> {
+    let (name, fields, length) = decode(opcode, PROBE)?;
+    let (_, second_fields, _) =
+        decode(opcode, SECOND_PROBE).expect("operands do not affect validity");
+    let operands: Vec<String> = fields
+        .iter()
+        .zip(&second_fields)
+        .enumerate()
+        .map(|(index, (field, second))| operand(&name, index, field, field == second))
+        .collect();
+    Some(format!(
+        "{{\"opcode\": {opcode}, \"name\": \"{name}\", \"length\": {length}, \"operands\": [{}]}}",
+        operands.join(", ")
+    ))
+}
+
+/// Decode `opcode` followed by `operands`, returning the variant name and the `Debug` form of each field, from
+/// `Name(field, field)`, together with the length.
+fn decode(opcode: u8, operands: [u8; 3]) -> Option<(String, Vec<String>, u32)> {
+    let bytes = [opcode, operands[0], operands[1], operands[2]];
+    let (instruction, length) = Instruction::try_from_iter(bytes.iter()).ok()?;
+    let debug = format!("{instruction:?}");
+    let (name, fields) = match debug.split_once('(') {
+        Some((name, fields)) => (name, fields.trim_end_matches(')')),
+        None => (debug.as_str(), ""),
+    };
+    let fields = fields
+        .split(", ")
+        .filter(|field| !field.is_empty())
+        .map(str::to_string)
+        .collect();
+    Some((name.to_string(), fields, length))
+}
+
+/// The JSON for field `index` of instruction `name`, whose `Debug` form is `field`. `fixed` fields are determined by
+/// the opcode alone.
+fn operand(name: &str, index: usize, field: &str, fixed: bool) -> String {
+    let kind = if matches!(field, "A" | "B" | "C" | "D") {
+        "register"
+    } else if name.ends_with("If") && index == 0 {
+        "condition"
+    } else if matches!(name, "Set" | "Clear") {
+        "flag"
+    } else {
+        "number"
+    };
+    if fixed {
+        let value = match kind {
+            "register" => format!("\"{field}\""),
+            _ => field.to_string(),
+        };
+        return format!("{{\"kind\": \"{kind}\", \"value\": {value}}}");
+    }
+    let (offset, size) = match field.parse::<i32>().map(|value| value as u16) {
+        Ok(0x05) => (1, 1),
+        Ok(0x34) => (2, 1),
+        Ok(0x3405) => (1, 2),
+        Ok(0x1234) => (2, 2),
+        _ => panic!("{name}: cannot locate operand `{field}`"),
+    };
+    format!("{{\"kind\": \"{kind}\", \"offset\": {offset}, \"size\": {size}}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operands_are_located_by_offset_and_size() {
+        assert_eq!(
+            describe(0xB8).unwrap(),
+            r#"{"opcode": 184, "name": "CallIf", "length": 4, "operands": [{"kind": "condition", "offset": 1, "size": 1}, {"kind": "number", "offset": 2, "size": 2}]}"#
+        );
+        assert_eq!(
+            describe(0x0D).unwrap(),
+            r#"{"opcode": 13, "name": "LoadImmediate", "length": 3, "operands": [{"kind": "register", "value": "B"}, {"kind": "number", "offset": 1, "size": 2}]}"#
+        );
+        assert_eq!(
+            describe(0xFF).unwrap(),
+            r#"{"opcode": 255, "name": "Set", "length": 1, "operands": [{"kind": "flag", "value": 15}]}"#
+        );
+        assert_eq!(describe(0x63), None);
+    }
+}
@@ -72,4 +72,39 @@ pub const GREATER_EQUAL: u8 = 14;
 /// Zero flag is clear and sign flag is equal to overflow flag. Equivalent to `[condition::GREATER]`.
 pub const NOT_LESS_EQUAL: u8 = 15;
 /// Zero flag is clear and sign flag is equal to overflow flag. Equivalent to `[condition::NOT_LESS_EQUAL]`.
-pub const GREATER: u8 = 15;
\ No newline at end of file
+pub const GREATER: u8 = 15;
+
+/// Names accepted by the assembler for each condition, indexed by condition code.
+///
+/// The first name is canonical and the rest are aliases. Reserved conditions have no names.
+pub const NAMES: [&[&str]; 16] = [
+    &["Z", "E"],
+    &["S"],
+    &["C", "B", "NAE"],
+    &["O"],
+    &[],
+    &["BE", "NA"],
+    &["L", "NGE"],
+    &["LE", "NG"],
+    &["NZ", "NE"],
+    &["NS"],
+    &["NC", "AE", "NB"],
+    &["NO"],
+    &[],
+    &["A", "NBE"],
+    &["GE", "NL"],
+    &["G", "NLE"],
+];
+
+/// Canonical name of a condition, or `None` if it is reserved or out of range.
+pub fn name(cond: u8) -> Option<&'static str> {
+    NAMES.get(cond as usize)?.first().copied()
+}
+
+/// Look up a condition by any of its names, ignoring case.
+pub fn from_name(name: &str) -> Option<u8> {
+    NAMES
+        .iter()
+        .position(|names| names.iter().any(|n| n.eq_ignore_ascii_case(name)))
+        .map(|cond| cond as u8)
+}
@@ -0,0 +1,111 @@
+//! Line tables, which map addresses in an image back to the source lines they were assembled from, so a debugger can
+//! show source instead of bytes.
+//!
+//! A line table is stored as `LINE` followed by one 10-byte entry per run of bytes, each holding the address, the
+//! length, the line and the column, little-endian, as a `u16`, `u16`, `u32` and `u16`.
+
+/// Identifies a line table file.
+const MAGIC: &[u8; 4] = b"LINE";
+/// Size of an entry when stored.
+const ENTRY_SIZE: usize = 10;
+
+/// A run of bytes in the image and where in the source it came from. Lines and columns start at 1.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct LineEntry {
+    pub address: u16,
+    pub length: u16,
+    pub line: u32,
+    pub column: u16,
+}
+
+impl LineEntry {
+    fn contains(&self, address: u16) -> bool {
+        address.wrapping_sub(self.address) < self.length
+    }
+}
+
+/// Every run of bytes in an image with the source line it came from, sorted by address.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct LineTable {
+    entries: Vec<LineEntry>,
+}
+
+impl LineTable {
+    pub fn new(mut entries: Vec<LineEntry>) -> Self {
+        entries.sort_by_key(|entry| entry.address);
+        Self { entries }
+    }
+
+    pub fn entries(&self) -> &[LineEntry] {
+        &self.entries
+    }
+
+    /// The entry covering `address`, if any.
+    pub fn lookup(&self, address: u16) -> Option<&LineEntry> {
+        let index = self
+            .entries
+            .partition_point(|entry| entry.address <= address);
+        self.entries[..index]
+            .last()
+            .filter(|entry| entry.contains(address))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for entry in &self.entries {
+            bytes.extend_from_slice(&entry.address.to_le_bytes());
+            bytes.extend_from_slice(&entry.length.to_le_bytes());
+            bytes.extend_from_slice(&entry.line.to_le_bytes());
+            bytes.extend_from_slice(&entry.column.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Read a table written by [`LineTable::to_bytes`], or `None` if `bytes` is not one, including if an entry is on
+    /// line 0.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let entries = bytes.strip_prefix(MAGIC)?;
+        if entries.len() % ENTRY_SIZE != 0 {
+            return None;
+        }
+        let entries = entries
+            .chunks(ENTRY_SIZE)
+            .map(|entry| LineEntry {
+                address: u16::from_le_bytes([entry[0], entry[1]]),
+                length: u16::from_le_bytes([entry[2], entry[3]]),
+                line: u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+                column: u16::from_le_bytes([entry[8], entry[9]]),
+            })
+            .collect::<Vec<_>>();
+        if entries.iter().any(|entry| entry.line == 0) {
+            return None;
+        }
+        Some(Self::new(entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(line: u32) -> LineEntry {
+        LineEntry {
+            address: 0x10,
+            length: 3,
+            line,
+            column: 5,
+        }
+    }
+
+    #[test]
+    fn tables_read_back_what_was_written() {
+        let table = LineTable::new(vec![entry(1)]);
+        assert_eq!(LineTable::from_bytes(&table.to_bytes()), Some(table));
+    }
+
+    #[test]
+    fn tables_on_line_0_are_rejected() {
+        let bytes = LineTable::new(vec![entry(1), entry(0)]).to_bytes();
+        assert_eq!(LineTable::from_bytes(&bytes), None);
+    }
+}
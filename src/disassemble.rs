@@ -0,0 +1,89 @@
+//! Decode machine code back into assembler source, the inverse of [`crate::assemble`].
+
+use crate::isa::Instruction;
+use std::collections::HashMap;
+
+/// An instruction decoded from an image, or a byte that does not start a valid one.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Decoded {
+    pub address: u16,
+    /// The bytes of the instruction, or the single byte that could not be decoded.
+    pub bytes: Vec<u8>,
+    pub instruction: Option<Instruction>,
+}
+
+impl Decoded {
+    /// The address of the next instruction.
+    pub fn next(&self) -> u16 {
+        self.address.wrapping_add(self.bytes.len() as u16)
+    }
+
+    /// The instruction in assembler syntax, or a `.db` directive for an undecodable byte.
+    ///
+    /// Branch targets are written as addresses, even for relative branches, since the address of the instruction is
+    /// known. A branch target or memory address that `symbols` has a name for is written as that name, so the text
+    /// still assembles to the same bytes when the symbols are defined.
+    pub fn text(&self, symbols: &HashMap<u16, String>) -> String {
+        use Instruction::*;
+        let Some(instruction) = self.instruction else {
+            return format!(".db ${:02X}", self.bytes[0]);
+        };
+        let text = instruction.to_string();
+        if let Some(target) = instruction.branch_target(self.next()) {
+            // The target is always the last operand.
+            let (head, _) = text.rsplit_once(' ').expect("branches have an operand");
+            return match symbols.get(&target) {
+                Some(name) => format!("{head} {name}"),
+                None => format!("{head} ${target:04X}"),
+            };
+        }
+        match instruction {
+            LoadAddress(address)
+            | LoadByteAddress(address)
+            | StoreAddress(address)
+            | StoreByteAddress(address) => match symbols.get(&address) {
+                Some(name) => text.replace(&format!("[${address:04X}]"), &format!("[{name}]")),
+                None => text,
+            },
+            _ => text,
+        }
+    }
+}
+
+/// Decode `bytes`, loaded at `base_address`, from the start. A byte that does not start a valid instruction, including
+/// one cut off by the end of `bytes`, is taken on its own and decoding carries on after it.
+pub fn disassemble(bytes: &[u8], base_address: u16) -> Vec<Decoded> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (instruction, length) = match Instruction::try_from_iter(&bytes[offset..]) {
+            Ok((instruction, length)) => (Some(instruction), length as usize),
+            Err(_) => (None, 1),
+        };
+        result.push(Decoded {
+            address: base_address.wrapping_add(offset as u16),
+            bytes: bytes[offset..offset + length].to_vec(),
+            instruction,
+        });
+        offset += length;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::{START_ADDRESS, compile};
+
+    #[test]
+    fn text_assembles_back_to_the_same_bytes() {
+        // `NOP`, an invalid opcode, `JR` back to the start, and `HALT`.
+        let bytes = [0xD3, 0x63, 0x62, 0xFB, 0xFF, 0xFF];
+        let source: String = disassemble(&bytes, START_ADDRESS)
+            .iter()
+            .map(|decoded| format!("    {}\n", decoded.text(&HashMap::new())))
+            .collect();
+        assert!(source.contains(".db $63"), "{source}");
+        assert_eq!(compile(&source).unwrap().0, bytes);
+    }
+}
@@ -0,0 +1,185 @@
+//! Export a memory image as a minimal 32-bit little-endian ELF executable, so generic binary tools can load it.
+//!
+//! The file has one loadable segment holding the image at its load address, and a symbol table. ELF has no machine
+//! number for this processor, so `e_machine` is `EM_NONE`.
+
+/// Size of the ELF header.
+const HEADER_SIZE: u32 = 52;
+/// Size of a program header.
+const PROGRAM_HEADER_SIZE: u32 = 32;
+/// Size of a section header.
+const SECTION_HEADER_SIZE: u32 = 40;
+/// Size of a symbol table entry.
+const SYMBOL_SIZE: u32 = 16;
+
+/// Names of the sections after the null section, in order.
+const SECTION_NAMES: &[u8] = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+const TEXT_NAME: u32 = 1;
+const SYMTAB_NAME: u32 = 7;
+const STRTAB_NAME: u32 = 15;
+const SHSTRTAB_NAME: u32 = 23;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+/// `SHF_WRITE | SHF_ALLOC | SHF_EXECINSTR`: the image mixes code and data.
+const TEXT_FLAGS: u32 = 7;
+const SHN_ABS: u16 = 0xFFF1;
+/// `STB_GLOBAL` binding with `STT_NOTYPE` type.
+const SYMBOL_INFO: u8 = 0x10;
+
+/// Build an ELF file holding `image` loaded at `address`, with `entry` as the entry point and `symbols` in its symbol
+/// table. Symbols whose value falls inside the image belong to `.text`; the rest, such as constants, are absolute.
+pub fn write(image: &[u8], address: u16, entry: u16, symbols: &[(String, u16)]) -> Vec<u8> {
+    let mut names = vec![0];
+    let mut symtab = vec![0; SYMBOL_SIZE as usize];
+    let end = address as usize + image.len();
+    for (name, value) in symbols {
+        let section = if (address as usize..end).contains(&(*value as usize)) {
+            1
+        } else {
+            SHN_ABS
+        };
+        put32(&mut symtab, names.len() as u32);
+        put32(&mut symtab, *value as u32);
+        put32(&mut symtab, 0);
+        symtab.push(SYMBOL_INFO);
+        symtab.push(0);
+        put16(&mut symtab, section);
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+
+    let text_offset = HEADER_SIZE + PROGRAM_HEADER_SIZE;
+    let symtab_offset = align4(text_offset + image.len() as u32);
+    let strtab_offset = symtab_offset + symtab.len() as u32;
+    let shstrtab_offset = strtab_offset + names.len() as u32;
+    let section_offset = align4(shstrtab_offset + SECTION_NAMES.len() as u32);
+
+    let mut elf = Vec::new();
+    elf.extend_from_slice(b"\x7FELF");
+    // 32-bit, little-endian, version 1, System V ABI, padded to 16 bytes.
+    elf.extend_from_slice(&[1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    put16(&mut elf, 2); // e_type: ET_EXEC
+    put16(&mut elf, 0); // e_machine: EM_NONE
+    put32(&mut elf, 1); // e_version
+    put32(&mut elf, entry as u32);
+    put32(&mut elf, HEADER_SIZE); // e_phoff
+    put32(&mut elf, section_offset); // e_shoff
+    put32(&mut elf, 0); // e_flags
+    put16(&mut elf, HEADER_SIZE as u16);
+    put16(&mut elf, PROGRAM_HEADER_SIZE as u16);
+    put16(&mut elf, 1); // e_phnum
+    put16(&mut elf, SECTION_HEADER_SIZE as u16);
+    put16(&mut elf, 5); // e_shnum
+    put16(&mut elf, 4); // e_shstrndx
+
+    // A single PT_LOAD segment, readable, writable and executable.
+    put32(&mut elf, 1); // p_type: PT_LOAD
+    put32(&mut elf, text_offset);
+    put32(&mut elf, address as u32); // p_vaddr
+    put32(&mut elf, address as u32); // p_paddr
+    put32(&mut elf, image.len() as u32); // p_filesz
+    put32(&mut elf, image.len() as u32); // p_memsz
+    put32(&mut elf, 7); // p_flags: PF_R | PF_W | PF_X
+    put32(&mut elf, 1); // p_align
+
+    elf.extend_from_slice(image);
+    elf.resize(symtab_offset as usize, 0);
+    elf.extend_from_slice(&symtab);
+    elf.extend_from_slice(&names);
+    elf.extend_from_slice(SECTION_NAMES);
+    elf.resize(section_offset as usize, 0);
+
+    let sections = [
+        SectionHeader::default(),
+        SectionHeader {
+            name: TEXT_NAME,
+            kind: SHT_PROGBITS,
+            flags: TEXT_FLAGS,
+            address: address as u32,
+            offset: text_offset,
+            size: image.len() as u32,
+            align: 1,
+            ..SectionHeader::default()
+        },
+        SectionHeader {
+            name: SYMTAB_NAME,
+            kind: SHT_SYMTAB,
+            offset: symtab_offset,
+            size: symtab.len() as u32,
+            // The string table is section 3. Every symbol is global, so the first global is entry 1.
+            link: 3,
+            info: 1,
+            align: 4,
+            entry_size: SYMBOL_SIZE,
+            ..SectionHeader::default()
+        },
+        SectionHeader {
+            name: STRTAB_NAME,
+            kind: SHT_STRTAB,
+            offset: strtab_offset,
+            size: names.len() as u32,
+            align: 1,
+            ..SectionHeader::default()
+        },
+        SectionHeader {
+            name: SHSTRTAB_NAME,
+            kind: SHT_STRTAB,
+            offset: shstrtab_offset,
+            size: SECTION_NAMES.len() as u32,
+            align: 1,
+            ..SectionHeader::default()
+        },
+    ];
+    for section in sections {
+        section.write(&mut elf);
+    }
+    elf
+}
+
+/// An `Elf32_Shdr`.
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    address: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    align: u32,
+    entry_size: u32,
+}
+
+impl SectionHeader {
+    fn write(&self, buffer: &mut Vec<u8>) {
+        for field in [
+            self.name,
+            self.kind,
+            self.flags,
+            self.address,
+            self.offset,
+            self.size,
+            self.link,
+            self.info,
+            self.align,
+            self.entry_size,
+        ] {
+            put32(buffer, field);
+        }
+    }
+}
+
+fn put16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn align4(offset: u32) -> u32 {
+    offset.next_multiple_of(4)
+}
@@ -1,12 +1,241 @@
+use crate::debug_info::{LineEntry, LineTable};
+use crate::flag::{self, Flags};
 use crate::isa::{Instruction, InstructionError};
-use crate::flag;
+use crate::memory::{Memory, MemoryExt};
+use crate::port::{PortDevice, Ports};
 use crate::register::GeneralPurposeRegister;
-use crate::memory::Memory;
+use std::fmt::Display;
 
 pub const MEM_SIZE: usize = 0x10000;
+/// Initial stack pointer. The stack grows downwards from here.
+pub const STACK_TOP: u16 = 0xF000;
+/// Address of the vector table used by [`Instruction::Int`], one word per interrupt number.
+pub const SOFTWARE_INTERRUPT_VECTORS: u16 = 0xFFE0;
+/// Address of the vector taken when user-mode code executes a privileged instruction or writes to read-only memory. A
+/// vector of 0 stops the emulator with [`StopReason::PrivilegeFault`] instead.
+pub const PRIVILEGE_FAULT_VECTOR: u16 = 0xFFF0;
+/// Address of the vector taken when the bytes at the program counter are not a valid instruction. A vector of 0
+/// halts the emulator instead.
+pub const INVALID_OPCODE_VECTOR: u16 = 0xFFF2;
+/// Address of the vector taken when memory refuses a word access, under
+/// [`WordPolicy::Fault`](crate::memory::WordPolicy::Fault), or an instruction is fetched from unmapped memory. A vector
+/// of 0 stops the emulator with [`StopReason::BusFault`] instead.
+///
+/// Bus faults are precise: the faulting instruction has no effect on the registers, so the handler can return to it
+/// to try again. Memory below the stack pointer is not part of that state, and may hold words the instruction pushed
+/// before it faulted.
+pub const BUS_FAULT_VECTOR: u16 = 0xFFF4;
+/// Address of the interrupt mask, a word with one bit per interrupt line. Requests on a line whose bit is set wait
+/// until it is cleared.
+pub const INTERRUPT_MASK: u16 = 0xFFFA;
+/// Number of hardware interrupt lines. A request from source `n` is made on line `n % IRQ_LINES`.
+pub const IRQ_LINES: usize = 8;
 
+/// Layout of a machine that is not part of its memory map, checked against the memory by [`Emulator::with_config`].
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct MachineConfig {
+    /// Initial stack pointer. The stack grows downwards from here, and 0 starts it at the top of the 16-bit address
+    /// space.
+    pub stack_top: u16,
+    /// Bytes set aside for the stack below `stack_top`, all of which must be writable memory.
+    pub stack_size: u16,
+}
+
+impl Default for MachineConfig {
+    /// A 256-byte stack below [`STACK_TOP`].
+    fn default() -> Self {
+        Self {
+            stack_top: STACK_TOP,
+            stack_size: 0x100,
+        }
+    }
+}
+
+/// Why a [`MachineConfig`] does not fit the memory it was given.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum ConfigError {
+    /// The stack region runs below address 0 or past the end of memory, which is `len` bytes long.
+    StackOutsideMemory {
+        stack_top: u16,
+        stack_size: u16,
+        len: usize,
+    },
+    /// An address in the stack region is read-only or unmapped.
+    StackNotWritable(u16),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::StackOutsideMemory {
+                stack_top,
+                stack_size,
+                len,
+            } => {
+                write!(
+                    f,
+                    "a {stack_size}-byte stack below ${stack_top:04X} does not fit in memory ({len:#X} bytes)"
+                )
+            }
+            ConfigError::StackNotWritable(address) => {
+                write!(f, "stack address ${address:04X} is not writable")
+            }
+        }
+    }
+}
+
+/// Why [`Emulator::run`] stopped.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum StopReason {
+    /// The halt flag was set.
+    Halt,
+    /// The halt flag was set together with the break flag, as `Brk` does.
+    Break,
+    /// The bytes at the program counter are not a valid instruction.
+    InvalidInstruction(InstructionError),
+    /// The step limit was reached.
+    StepLimit,
+    /// The [`Watchdog`] saw execution go round the code from `start` to `end`, inclusive, without making progress. The
+    /// code wraps past $FFFF to $0000 if `end` is below `start`.
+    Stuck { start: u16, end: u16 },
+    /// A supervisor-mode instruction wrote to read-only memory while [`Emulator::stop_on_rom_write`] was set.
+    RomWrite(RomWrite),
+    /// Memory refused a word access or an instruction fetch at this address, and there is no handler at
+    /// [`BUS_FAULT_VECTOR`].
+    BusFault(u16),
+    /// User-mode code executed a privileged instruction or wrote to read-only memory, and there is no handler at
+    /// [`PRIVILEGE_FAULT_VECTOR`].
+    PrivilegeFault,
+}
+
+impl StopReason {
+    /// The reason as a JSON object: its kind in snake case, and its details as further fields.
+    pub fn to_json(&self) -> String {
+        match self {
+            StopReason::Halt => r#"{"kind":"halt"}"#.to_string(),
+            StopReason::Break => r#"{"kind":"break"}"#.to_string(),
+            StopReason::InvalidInstruction(err) => {
+                let opcode = match err {
+                    InstructionError::InvalidOpcode(opcode) => Some(opcode),
+                    InstructionError::EndOfInput => None,
+                };
+                format!(
+                    r#"{{"kind":"invalid_instruction","opcode":{}}}"#,
+                    json_option(opcode)
+                )
+            }
+            StopReason::StepLimit => r#"{"kind":"step_limit"}"#.to_string(),
+            StopReason::Stuck { start, end } => {
+                format!(r#"{{"kind":"stuck","start":{start},"end":{end}}}"#)
+            }
+            StopReason::RomWrite(write) => {
+                format!(r#"{{"kind":"rom_write","write":{}}}"#, write.to_json())
+            }
+            StopReason::BusFault(address) => {
+                format!(r#"{{"kind":"bus_fault","address":{address}}}"#)
+            }
+            StopReason::PrivilegeFault => r#"{"kind":"privilege_fault"}"#.to_string(),
+        }
+    }
+}
+
+/// A write to read-only memory, which the memory ignored, with the instruction that made it.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct RomWrite {
+    /// Address of the instruction.
+    pub pc: u16,
+    /// Number of instructions executed before it in the run.
+    pub step: u64,
+    pub address: u16,
+    pub value: u8,
+}
+
+impl RomWrite {
+    /// The write as a JSON object with the same fields.
+    pub fn to_json(&self) -> String {
+        let RomWrite {
+            pc,
+            step,
+            address,
+            value,
+        } = self;
+        format!(r#"{{"pc":{pc},"step":{step},"address":{address},"value":{value}}}"#)
+    }
+}
+
+/// Detects accidental infinite loops in [`Emulator::run_watched`].
+///
+/// Execution counts as stuck once it has stayed within `window` bytes of code for `steps` instructions in a row
+/// without writing memory, using a port, or taking an interrupt. A watchdog with `steps` of 0 is disabled.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Watchdog {
+    pub window: u16,
+    pub steps: u64,
+}
+
+/// The outcome of [`Emulator::run`] and the other run APIs.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct RunResult {
+    pub reason: StopReason,
+    /// Number of instructions executed.
+    pub steps: u64,
+    /// Number of cycles the emulator has run for over its whole life, as [`Emulator::cycles`].
+    pub cycles: u64,
+    /// Deepest the stack has been, in bytes, over the emulator's whole life.
+    pub max_stack_depth: u16,
+    /// Program counter when execution stopped. For an invalid or faulting instruction, this is its address.
+    pub pc: u16,
+    /// Number of bytes written to read-only memory in supervisor mode. User-mode writes fault instead.
+    pub rom_writes: u64,
+    /// The first write to read-only memory, to find the code responsible.
+    pub first_rom_write: Option<RomWrite>,
+    /// Source of the last hardware interrupt taken, as given to [`Emulator::interrupt`].
+    pub irq: Option<u16>,
+}
+
+impl RunResult {
+    /// The address whose access stopped execution: the invalid instruction, the read-only byte written, or the word
+    /// memory refused.
+    pub fn fault_address(&self) -> Option<u16> {
+        match self.reason {
+            StopReason::InvalidInstruction(_) | StopReason::PrivilegeFault => Some(self.pc),
+            StopReason::RomWrite(write) => Some(write.address),
+            StopReason::BusFault(address) => Some(address),
+            _ => None,
+        }
+    }
+
+    /// The result as a single-line JSON object, with the same fields and the fault address. Addresses and values are
+    /// plain numbers, and a missing value is `null`.
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"reason":{},"steps":{},"cycles":{},"max_stack_depth":{},"pc":{},"rom_writes":{},"first_rom_write":{},"irq":{},"fault_address":{}}}"#,
+            self.reason.to_json(),
+            self.steps,
+            self.cycles,
+            self.max_stack_depth,
+            self.pc,
+            self.rom_writes,
+            json_option(self.first_rom_write.map(|write| write.to_json())),
+            json_option(self.irq),
+            json_option(self.fault_address()),
+        )
+    }
+}
+
+/// A JSON value, or `null` for `None`.
+fn json_option(value: Option<impl Display>) -> String {
+    value.map_or("null".to_string(), |value| value.to_string())
+}
+
+/// A processor with its memory and port devices.
+///
+/// An emulator can be cloned, compared and hashed only when its memory and port device can. [`Ports`] holds arbitrary
+/// devices and cannot, so the default emulator is none of these. To snapshot or compare emulators, give them the
+/// [`Console`](crate::port::Console) as their port device instead: it is what [`Ports::default`] attaches to every port, so
+/// `Emulator::with_ports(memory, STACK_TOP, Console)` behaves the same as `Emulator::new(memory)`.
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
-pub struct Emulator<M: Memory = [u8; MEM_SIZE]> {
+pub struct Emulator<M: Memory = [u8; MEM_SIZE], P: PortDevice = Ports> {
     /// Accumulator (operations)
     pub a: u16,
     /// Base (addresses)
@@ -19,24 +248,100 @@ pub struct Emulator<M: Memory = [u8; MEM_SIZE]> {
     pub pc: u16,
     /// Stack Pointer
     pub sp: u16,
+    /// Initial stack pointer
+    pub stack_top: u16,
+    /// Deepest the stack has been, in bytes below `stack_top`
+    pub max_stack_depth: u16,
+    /// Instructions executed over the emulator's whole life, counting an invalid one that entered its handler. Every
+    /// instruction takes one cycle.
+    pub cycles: u64,
     /// Program Flags
-    pub flags: u16,
+    pub flags: Flags,
+    /// Source of the request waiting on each interrupt line, as given to [`Emulator::interrupt`]
+    pub pending: [Option<u16>; IRQ_LINES],
     /// Program Memory
     pub memory: M,
+    /// Devices reached through `Input` and `Output`
+    pub ports: P,
+    /// Stop [`Emulator::run`] with [`StopReason::RomWrite`] when an instruction writes to read-only memory
+    pub stop_on_rom_write: bool,
 }
 
 impl<M: Memory> Emulator<M> {
+    /// Create an emulator with its stack at [`STACK_TOP`].
+    ///
+    /// # Panics
+    ///
+    /// If the first word pushed would not be writable, as when `memory` is smaller than [`STACK_TOP`]. Use
+    /// [`Emulator::with_config`] to get an error instead.
     pub fn new(memory: M) -> Self {
-        Self {
+        Self::with_stack(memory, STACK_TOP)
+    }
+
+    /// Create an emulator whose stack starts at `stack_top` instead of [`STACK_TOP`]. A `stack_top` of 0 starts the
+    /// stack at the top of the 16-bit address space.
+    ///
+    /// # Panics
+    ///
+    /// If the first word pushed would not be writable.
+    pub fn with_stack(memory: M, stack_top: u16) -> Self {
+        Self::with_ports(memory, stack_top, Ports::default())
+    }
+}
+
+impl<M: Memory, P: PortDevice> Emulator<M, P> {
+    /// Create an emulator with its stack at `stack_top` and `ports` in place of the default [`Ports`].
+    ///
+    /// # Panics
+    ///
+    /// Under the same conditions as [`Emulator::with_stack`].
+    pub fn with_ports(memory: M, stack_top: u16, ports: P) -> Self {
+        let config = MachineConfig {
+            stack_top,
+            stack_size: 2,
+        };
+        Self::with_config(memory, ports, config).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Create an emulator laid out as `config` says, or say why `config` does not fit `memory`.
+    pub fn with_config(memory: M, ports: P, config: MachineConfig) -> Result<Self, ConfigError> {
+        let MachineConfig {
+            stack_top,
+            stack_size,
+        } = config;
+        let start = stack_top.wrapping_sub(stack_size) as usize;
+        let end = start + stack_size as usize;
+        let top = if stack_top == 0 {
+            MEM_SIZE
+        } else {
+            stack_top as usize
+        };
+        if end != top || end > memory.len() {
+            return Err(ConfigError::StackOutsideMemory {
+                stack_top,
+                stack_size,
+                len: memory.len(),
+            });
+        }
+        if let Some(address) = (start..end).find(|&address| !memory.is_writable(address)) {
+            return Err(ConfigError::StackNotWritable(address as u16));
+        }
+        Ok(Self {
             a: 0,
             b: 0,
             c: 0,
             d: 0,
             pc: 0,
-            sp: 0xF000,
-            flags: 0,
+            sp: stack_top,
+            stack_top,
+            max_stack_depth: 0,
+            cycles: 0,
+            flags: Flags::default(),
+            pending: [None; IRQ_LINES],
             memory,
-        }
+            ports,
+            stop_on_rom_write: false,
+        })
     }
 
     pub fn register(&self, reg: GeneralPurposeRegister) -> u16 {
@@ -58,122 +363,391 @@ impl<M: Memory> Emulator<M> {
     }
 
     pub fn next_instruction(&self) -> Result<(Instruction, u32), InstructionError> {
-        Instruction::try_from_iter(self.memory.read_array::<3>(self.pc as usize).iter())
+        Instruction::try_from_iter(self.memory.read_array::<4>(self.pc as usize).iter())
+    }
+
+    /// Where in the source the next instruction was assembled from, according to `lines`.
+    pub fn source_line<'t>(&self, lines: &'t LineTable) -> Option<&'t LineEntry> {
+        lines.lookup(self.pc)
     }
 
     pub fn advance(&mut self) {
-        let (instruction, count) = self.next_instruction().unwrap();
+        let _ = self.execute_next();
+    }
+
+    /// Fetch and execute the next instruction, then take any fault or interrupt it caused. Returns the source of the
+    /// interrupt taken, if any.
+    fn execute_next(&mut self) -> Result<Option<u16>, StopReason> {
+        let (instruction, count) = match self.next_instruction() {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                if !self.invalid_opcode() {
+                    return Err(StopReason::InvalidInstruction(err));
+                }
+                self.cycles += 1;
+                return Ok(None);
+            }
+        };
+        let unmapped = (0..count as u16)
+            .map(|offset| self.pc.wrapping_add(offset))
+            .find(|&address| !self.memory.is_mapped(address as usize));
+        if let Some(address) = unmapped {
+            if !self.bus_fault() {
+                return Err(StopReason::BusFault(address));
+            }
+            self.cycles += 1;
+            return Ok(None);
+        }
+        self.advance_with(instruction, count)
+    }
+
+    /// Execute `instruction`, `count` bytes long, then take any bus fault or interrupt it caused. Returns the source of
+    /// the interrupt taken, if any.
+    fn advance_with(
+        &mut self,
+        instruction: Instruction,
+        count: u32,
+    ) -> Result<Option<u16>, StopReason> {
+        // Faults from outside any instruction, such as pushing a handler's frame, have nothing to undo.
+        self.memory.take_word_faults();
+        let registers = (self.a, self.b, self.c, self.d, self.pc, self.sp, self.flags);
         self.pc = self.pc.wrapping_add(count as u16);
-        self.execute(instruction);
-        if self.flags & (1 << flag::INTERRUPT) != 0 {
-            self.handle_interrupt();
+        let user = self.flags.get(flag::USER);
+        let privileged = user && instruction.is_privileged();
+        if !privileged {
+            self.execute(instruction);
+        }
+        let faults = self.memory.take_word_faults();
+        if let Some(&address) = faults.first() {
+            // Undo the instruction, so that the handler sees the state from before it.
+            (self.a, self.b, self.c, self.d, self.pc, self.sp, self.flags) = registers;
+            if !self.bus_fault() {
+                return Err(StopReason::BusFault(address as u16));
+            }
+        } else if privileged || user && !self.memory.take_read_only_writes().is_empty() {
+            // Read-only memory has already ignored any write, so only the fault is left to take.
+            if !self.privilege_fault() {
+                self.pc = registers.4;
+                return Err(StopReason::PrivilegeFault);
+            }
         }
+        self.cycles += 1;
+        let irq = self.take_interrupt();
+        self.max_stack_depth = self.max_stack_depth.max(self.stack_depth());
+        Ok(irq)
     }
 
-    pub fn set_operation_flags(&mut self, value: u16) {
-        self.flags &= !(1 << flag::ZERO | 1 << flag::SIGN | 1 << flag::CARRY | 1 << flag::OVERFLOW);
-        if value == 0 {
-            self.flags |= 1 << flag::ZERO;
+    /// Bytes currently on the stack. A stack pointer above `stack_top` counts as empty.
+    pub fn stack_depth(&self) -> u16 {
+        let depth = self.stack_top.wrapping_sub(self.sp);
+        if (depth as i16) < 0 { 0 } else { depth }
+    }
+
+    /// Execute one instruction, unless the emulator is halted or the next instruction is invalid. This is
+    /// `run(Some(1))`, with the step limit taken as success and any other result returned as the error.
+    pub fn step(&mut self) -> Result<(), RunResult> {
+        let result = self.run(Some(1));
+        match result.reason {
+            StopReason::StepLimit => Ok(()),
+            _ => Err(result),
         }
-        if value & 0x8000 != 0 {
-            self.flags |= 1 << flag::SIGN;
+    }
+
+    /// Execute one instruction like [`Emulator::step`], returning the source of any interrupt taken.
+    fn try_step(&mut self) -> Result<Option<u16>, StopReason> {
+        let mut irq = None;
+        if self.flags.get(flag::HALT) {
+            if self.flags.get(flag::BREAK) {
+                return Err(StopReason::Break);
+            }
+            // A request that can be taken wakes the processor, which goes on to run the handler.
+            irq = Some(self.take_interrupt().ok_or(StopReason::Halt)?);
         }
+        Ok(self.execute_next()?.or(irq))
     }
 
-    pub fn check_condition(&self, cond: u8) -> bool {
-        use crate::condition::*;
+    /// Execute instructions until the emulator stops or `max_steps` instructions have been executed.
+    pub fn run(&mut self, max_steps: Option<u64>) -> RunResult {
+        self.run_watched(max_steps, None)
+    }
 
-        #[allow(unreachable_patterns)]
-        match cond {
-            ZERO | EQUAL => {
-                self.flags & (1 << flag::ZERO) != 0
+    /// Like [`Emulator::run`], but also stop with [`StopReason::Stuck`] if `watchdog` sees an infinite loop.
+    pub fn run_watched(&mut self, max_steps: Option<u64>, watchdog: Option<Watchdog>) -> RunResult {
+        self.run_with(max_steps, watchdog, |_| {})
+    }
+
+    /// Like [`Emulator::run`], but call `trace` with the emulator before each instruction, as for a tracing or
+    /// stepping front end.
+    pub fn run_traced(&mut self, max_steps: Option<u64>, trace: impl FnMut(&Self)) -> RunResult {
+        self.run_with(max_steps, None, trace)
+    }
+
+    fn run_with(
+        &mut self,
+        max_steps: Option<u64>,
+        watchdog: Option<Watchdog>,
+        mut trace: impl FnMut(&Self),
+    ) -> RunResult {
+        let mut steps = 0;
+        let mut irq = None;
+        let (mut rom_writes, mut first_rom_write) = (0, None);
+        // Start and length of the code run since the last progress, and the instructions executed since.
+        let (mut low, mut span, mut quiet) = (self.pc, 0, 0);
+        let reason = loop {
+            if max_steps.is_some_and(|max| steps >= max) {
+                break StopReason::StepLimit;
             }
-            SIGN => {
-                self.flags & (1 << flag::SIGN) != 0
+            trace(self);
+            let pc = self.pc;
+            let mut progress = match self.next_instruction() {
+                Ok((instruction, _)) => {
+                    instruction.writes_memory()
+                        || matches!(instruction, Instruction::Input | Instruction::Output)
+                }
+                Err(_) => true,
+            };
+            match self.try_step() {
+                Ok(taken) => {
+                    progress |= taken.is_some();
+                    irq = taken.or(irq);
+                }
+                Err(reason) => break reason,
             }
-            CARRY | BELOW | NOT_ABOVE_EQUAL => {
-                self.flags & (1 << flag::CARRY) != 0
+            steps += 1;
+
+            let ignored = self.memory.take_read_only_writes();
+            if let Some(write) = ignored.first() {
+                let write = RomWrite {
+                    pc,
+                    step: steps - 1,
+                    address: write.address as u16,
+                    value: write.value,
+                };
+                rom_writes += ignored.len() as u64;
+                first_rom_write = first_rom_write.or(Some(write));
+                if self.stop_on_rom_write {
+                    break StopReason::RomWrite(write);
+                }
             }
-            OVERFLOW => {
-                self.flags & (1 << flag::OVERFLOW) != 0
+
+            let Some(watchdog) = watchdog.filter(|watchdog| watchdog.steps > 0) else {
+                continue;
+            };
+            if progress {
+                (low, span, quiet) = (self.pc, 0, 0);
+                continue;
             }
-            RESERVED_4 | RESERVED_NOT_12 => {
-                self.flags & (1 << flag::CARRY) != 0
+            (low, span) = widen(low, span, pc);
+            if span > watchdog.window {
+                (low, span, quiet) = (pc, 0, 0);
             }
-            BELOW_EQUAL | NOT_ABOVE => {
-                (self.flags & (1 << flag::CARRY) != 0)
-                || (self.flags & (1 << flag::ZERO) != 0)
+            quiet += 1;
+            // Forget the code that led into the loop, so only the loop itself is reported.
+            if quiet == watchdog.steps / 2 {
+                (low, span) = (pc, 0);
             }
-            LESS | NOT_GREATER_EQUAL => {
-                (self.flags & (1 << flag::SIGN) != 0)
-                != (self.flags & (1 << flag::OVERFLOW) != 0)
+            if quiet >= watchdog.steps {
+                break StopReason::Stuck {
+                    start: low,
+                    end: low.wrapping_add(span),
+                };
             }
+        };
+        RunResult {
+            reason,
+            steps,
+            cycles: self.cycles,
+            max_stack_depth: self.max_stack_depth,
+            pc: self.pc,
+            rom_writes,
+            first_rom_write,
+            irq,
+        }
+    }
+
+    pub fn set_operation_flags(&mut self, value: u16) {
+        self.flags.assign(flag::ZERO, value == 0);
+        self.flags.assign(flag::SIGN, value & 0x8000 != 0);
+        self.flags.clear(flag::CARRY);
+        self.flags.clear(flag::OVERFLOW);
+    }
+
+    pub fn check_condition(&self, cond: u8) -> bool {
+        use crate::condition::*;
+
+        let flags = self.flags;
+        #[allow(unreachable_patterns)]
+        match cond {
+            ZERO | EQUAL => flags.get(flag::ZERO),
+            SIGN => flags.get(flag::SIGN),
+            CARRY | BELOW | NOT_ABOVE_EQUAL => flags.get(flag::CARRY),
+            OVERFLOW => flags.get(flag::OVERFLOW),
+            RESERVED_4 | RESERVED_NOT_12 => flags.get(flag::CARRY),
+            BELOW_EQUAL | NOT_ABOVE => flags.get(flag::CARRY) || flags.get(flag::ZERO),
+            LESS | NOT_GREATER_EQUAL => flags.get(flag::SIGN) != flags.get(flag::OVERFLOW),
             LESS_EQUAL | NOT_GREATER => {
-                (self.flags & (1 << flag::ZERO) != 0)
-                || (self.flags & (1 << flag::SIGN) != 0)
-                != (self.flags & (1 << flag::OVERFLOW) != 0)
-            }
-            NOT_ZERO | NOT_EQUAL => {
-                self.flags & (1 << flag::ZERO) == 0
-            }
-            NOT_SIGN => {
-                self.flags & (1 << flag::SIGN) == 0
-            }
-            NOT_CARRY | ABOVE_EQUAL | NOT_BELOW => {
-                self.flags & (1 << flag::CARRY) == 0
-            }
-            NOT_OVERFLOW => {
-                self.flags & (1 << flag::OVERFLOW) == 0
-            }
-            RESERVED_12 | RESERVED_NOT_4 => {
-                self.flags & (1 << flag::CARRY) == 0
-            }
-            NOT_BELOW_EQUAL | ABOVE => {
-                (self.flags & (1 << flag::CARRY) == 0)
-                && (self.flags & (1 << flag::ZERO) == 0)
-            }
-            NOT_LESS | GREATER_EQUAL => {
-                (self.flags & (1 << flag::SIGN) != 0)
-                == (self.flags & (1 << flag::OVERFLOW) != 0)
+                flags.get(flag::ZERO) || flags.get(flag::SIGN) != flags.get(flag::OVERFLOW)
             }
+            NOT_ZERO | NOT_EQUAL => !flags.get(flag::ZERO),
+            NOT_SIGN => !flags.get(flag::SIGN),
+            NOT_CARRY | ABOVE_EQUAL | NOT_BELOW => !flags.get(flag::CARRY),
+            NOT_OVERFLOW => !flags.get(flag::OVERFLOW),
+            RESERVED_12 | RESERVED_NOT_4 => !flags.get(flag::CARRY),
+            NOT_BELOW_EQUAL | ABOVE => !flags.get(flag::CARRY) && !flags.get(flag::ZERO),
+            NOT_LESS | GREATER_EQUAL => flags.get(flag::SIGN) == flags.get(flag::OVERFLOW),
             NOT_LESS_EQUAL | GREATER => {
-                (self.flags & (1 << flag::ZERO) == 0)
-                && (self.flags & (1 << flag::SIGN) != 0)
-                == (self.flags & (1 << flag::OVERFLOW) != 0)
+                !flags.get(flag::ZERO) && flags.get(flag::SIGN) == flags.get(flag::OVERFLOW)
             }
             _ => unimplemented!("Invalid condition: {cond}"),
         }
     }
 
-    pub fn handle_interrupt(&mut self) {
-        for reg in [self.pc, self.flags, self.a, self.b, self.c, self.d] {
+    /// Push A, B, C, and D, in that order.
+    pub fn push_all(&mut self) {
+        for reg in [self.a, self.b, self.c, self.d] {
             self.sp = self.sp.wrapping_sub(2);
             self.memory.write_word(self.sp as usize, reg);
         }
+    }
+
+    /// Pop D, C, B, and A, undoing [`Emulator::push_all`].
+    pub fn pop_all(&mut self) {
+        for reg in [&mut self.d, &mut self.c, &mut self.b, &mut self.a] {
+            *reg = self.memory.read_word(self.sp as usize);
+            self.sp = self.sp.wrapping_add(2);
+        }
+    }
+
+    /// Push the program counter, flags, and registers in the order [`Emulator::handle_interrupt_return`] pops them.
+    fn push_interrupt_frame(&mut self) {
+        for reg in [self.pc, self.flags.0] {
+            self.sp = self.sp.wrapping_sub(2);
+            self.memory.write_word(self.sp as usize, reg);
+        }
+        self.push_all();
+    }
+
+    /// Enter the handler at the interrupt vector in supervisor mode.
+    pub fn handle_interrupt(&mut self) {
+        self.push_interrupt_frame();
         self.pc = self.memory.read_word(0xFFFE);
-        self.flags |= 1 << flag::INTERRUPT;
-        self.flags &= !(1 << flag::HALT);
+        self.flags.clear(flag::HALT);
+        self.flags.clear(flag::USER);
     }
 
+    /// Return from an interrupt, restoring the registers, flags, and program counter from its frame. Restoring the flags
+    /// restores the interrupt level, so a nested handler returns to the one it interrupted.
     pub fn handle_interrupt_return(&mut self) {
-        for reg in [&mut self.d, &mut self.c, &mut self.b, &mut self.a, &mut self.flags, &mut self.pc] {
+        self.pop_all();
+        for reg in [&mut self.flags.0, &mut self.pc] {
             *reg = self.memory.read_word(self.sp as usize);
             self.sp = self.sp.wrapping_add(2);
         }
-        self.flags &= !(1 << flag::INTERRUPT);
     }
 
-    pub fn interrupt(&mut self, port: u16) {
-        self.memory.write_word(0xFFFC, port);
-        self.flags |= 1 << flag::INTERRUPT;
+    /// Request a hardware interrupt from `source`, which the handler finds at $FFFC. The request waits on its line
+    /// while the line is masked by [`INTERRUPT_MASK`], requests are held off, or the handler for the same line or a
+    /// lower-numbered one is running. A later request on the same line replaces it.
+    pub fn interrupt(&mut self, source: u16) {
+        self.pending[source as usize % IRQ_LINES] = Some(source);
+    }
+
+    /// Take the request waiting on the lowest-numbered line that is neither masked nor below the interrupt level,
+    /// unless requests are held off. Its handler runs at the level of its line. Returns its source.
+    fn take_interrupt(&mut self) -> Option<u16> {
+        if self.flags.get(flag::INTERRUPT) || self.pending.iter().all(Option::is_none) {
+            return None;
+        }
+        let mask = self.memory.read_word(INTERRUPT_MASK as usize);
+        let lines = match self.flags.level() {
+            0 => IRQ_LINES,
+            level => level as usize - 1,
+        };
+        let line =
+            (0..lines).find(|&line| mask & 1 << line == 0 && self.pending[line].is_some())?;
+        let source = self.pending[line].take()?;
+        self.memory.write_word(0xFFFC, source);
+        self.handle_interrupt();
+        self.flags.set_level(line as u16 + 1);
+        Some(source)
+    }
+
+    /// Enter software interrupt `number` through [`SOFTWARE_INTERRUPT_VECTORS`]. Returns with `ReturnInterrupt`.
+    pub fn software_interrupt(&mut self, number: u8) {
+        self.push_interrupt_frame();
+        let vector = SOFTWARE_INTERRUPT_VECTORS.wrapping_add(2 * number as u16);
+        self.pc = self.memory.read_word(vector as usize);
+        self.flags.clear(flag::USER);
+    }
+
+    /// Enter the handler at [`INVALID_OPCODE_VECTOR`] in supervisor mode, or halt if there is none. The saved program
+    /// counter is the address of the invalid instruction.
+    ///
+    /// Returns whether the handler was entered.
+    pub fn invalid_opcode(&mut self) -> bool {
+        let vector = self.memory.read_word(INVALID_OPCODE_VECTOR as usize);
+        if vector == 0 {
+            self.halt();
+            return false;
+        }
+        self.push_interrupt_frame();
+        self.pc = vector;
+        self.flags.clear(flag::USER);
+        true
+    }
+
+    /// Enter the handler at [`BUS_FAULT_VECTOR`] in supervisor mode, or halt if there is none. The saved program
+    /// counter is the address of the faulting instruction, which has not taken effect.
+    ///
+    /// Returns whether the handler was entered.
+    pub fn bus_fault(&mut self) -> bool {
+        let vector = self.memory.read_word(BUS_FAULT_VECTOR as usize);
+        if vector == 0 {
+            self.halt();
+            return false;
+        }
+        self.push_interrupt_frame();
+        self.pc = vector;
+        self.flags.clear(flag::USER);
+        true
+    }
+
+    /// Enter the handler at [`PRIVILEGE_FAULT_VECTOR`] in supervisor mode, or halt if there is none. The saved program
+    /// counter is the address after the faulting instruction.
+    ///
+    /// Returns whether the handler was entered.
+    pub fn privilege_fault(&mut self) -> bool {
+        let vector = self.memory.read_word(PRIVILEGE_FAULT_VECTOR as usize);
+        if vector == 0 {
+            self.halt();
+            return false;
+        }
+        self.push_interrupt_frame();
+        self.pc = vector;
+        self.flags.clear(flag::USER);
+        true
     }
 
     pub fn halt(&mut self) {
-        self.flags |= 1 << flag::HALT;
+        self.flags.set(flag::HALT);
     }
 
     pub fn resume(&mut self) {
-        self.flags &= !(1 << flag::HALT);
+        self.flags.clear(flag::HALT);
+    }
+}
+
+/// The shortest run of addresses that holds both the `span` bytes after `start` and `address`, as its start and
+/// length. Runs wrap past $FFFF to $0000, so a loop over the top of memory stays short.
+fn widen(start: u16, span: u16, address: u16) -> (u16, u16) {
+    let forward = address.wrapping_sub(start);
+    let backward = start.wrapping_sub(address) as u32 + span as u32;
+    if forward <= span {
+        (start, span)
+    } else if forward as u32 <= backward {
+        (start, forward)
+    } else {
+        (address, backward as u16)
     }
 }
 
@@ -181,4 +755,407 @@ impl<M: Memory + std::default::Default> std::default::Default for Emulator<M> {
     fn default() -> Self {
         Self::new(M::default())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{SegmentedMemory, WordPolicy};
+
+    /// An emulator running `program` at address 0, in RAM split into two segments at $8000.
+    fn split_ram(program: &[u8], word_policy: WordPolicy) -> Emulator<SegmentedMemory> {
+        let mut low = vec![0; 0x8000];
+        low[..program.len()].copy_from_slice(program);
+        let mut memory = SegmentedMemory::with_policy(MEM_SIZE, word_policy);
+        memory.map(0, Box::new(low.into_boxed_slice()), false);
+        memory.map(0x8000, Box::new(vec![0; 0x8000].into_boxed_slice()), false);
+        Emulator::new(memory)
+    }
+
+    #[test]
+    fn emulators_on_the_console_can_be_snapshotted() {
+        fn snapshot<T: Clone + Eq + std::hash::Hash>(value: &T) -> T {
+            value.clone()
+        }
+        let emu = Emulator::with_ports([0; MEM_SIZE], STACK_TOP, crate::port::Console);
+        assert_eq!(snapshot(&emu), emu);
+    }
+
+    #[test]
+    fn step_returns_the_result_once_stopped() {
+        let mut memory = [0; MEM_SIZE];
+        memory[..2].copy_from_slice(&[0xD3, 0xFF]);
+        let mut emu = Emulator::new(memory);
+        assert_eq!(emu.step(), Ok(()));
+        assert_eq!(emu.step(), Ok(()));
+        let result = emu.step().unwrap_err();
+        assert_eq!(
+            (result.reason, result.steps, result.cycles, result.pc),
+            (StopReason::Halt, 0, 2, 2)
+        );
+    }
+
+    #[test]
+    fn run_result_names_the_interrupt_taken() {
+        // `NOP`, `HALT`, with a handler that only returns.
+        let mut memory = [0; MEM_SIZE];
+        memory[..2].copy_from_slice(&[0xD3, 0xFF]);
+        memory[0x100] = 0xD2;
+        let mut emu = Emulator::new(memory);
+        emu.memory.write_word(0xFFFE, 0x100);
+        emu.interrupt(5);
+        let result = emu.run(None);
+        assert_eq!(
+            (result.reason, result.steps, result.irq),
+            (StopReason::Halt, 3, Some(5))
+        );
+    }
+
+    #[test]
+    fn run_result_serializes_to_json() {
+        let result = RunResult {
+            reason: StopReason::BusFault(0x7FFF),
+            steps: 1,
+            cycles: 4,
+            max_stack_depth: 0,
+            pc: 3,
+            rom_writes: 0,
+            first_rom_write: None,
+            irq: Some(2),
+        };
+        assert_eq!(
+            result.to_json(),
+            r#"{"reason":{"kind":"bus_fault","address":32767},"steps":1,"cycles":4,"max_stack_depth":0,"pc":3,"rom_writes":0,"first_rom_write":null,"irq":2,"fault_address":32767}"#
+        );
+    }
+
+    /// An emulator running `program` at address 0 in RAM, with the stack at the top of RAM and ROM from $8000 holding
+    /// a privilege fault handler that halts at $8000.
+    fn ram_and_rom(program: &[u8]) -> Emulator<SegmentedMemory> {
+        let mut ram = vec![0; 0x8000];
+        ram[..program.len()].copy_from_slice(program);
+        let mut rom = vec![0; 0x8000];
+        rom[0] = 0xFF;
+        rom[PRIVILEGE_FAULT_VECTOR as usize - 0x8000..][..2]
+            .copy_from_slice(&0x8000u16.to_le_bytes());
+        let mut memory = SegmentedMemory::new(MEM_SIZE);
+        memory.map(0, Box::new(ram.into_boxed_slice()), false);
+        memory.map(0x8000, Box::new(rom.into_boxed_slice()), true);
+        Emulator::with_stack(memory, 0x8000)
+    }
+
+    #[test]
+    fn user_mode_rom_writes_fault() {
+        // `SET U`, `STA [$9000]`, `HALT`
+        let mut emu = ram_and_rom(&[0xFC, 0x18, 0x00, 0x90, 0xFF]);
+        let result = emu.run(None);
+        assert_eq!(
+            (result.reason, result.pc, result.rom_writes),
+            (StopReason::Halt, 0x8001, 0)
+        );
+        assert!(!emu.flags.get(flag::USER));
+    }
+
+    #[test]
+    fn supervisor_rom_writes_are_recorded() {
+        // `STA [$9000]`, `HALT`
+        let mut emu = ram_and_rom(&[0x18, 0x00, 0x90, 0xFF]);
+        let result = emu.run(None);
+        assert_eq!(
+            (result.reason, result.pc, result.rom_writes),
+            (StopReason::Halt, 4, 2)
+        );
+    }
+
+    #[test]
+    fn a_watchdog_without_steps_is_disabled() {
+        // `JR $`
+        let mut memory = [0; MEM_SIZE];
+        memory[..3].copy_from_slice(&[0x62, 0xFD, 0xFF]);
+        let mut emu = Emulator::new(memory);
+        let result = emu.run_watched(
+            Some(100),
+            Some(Watchdog {
+                window: 16,
+                steps: 0,
+            }),
+        );
+        assert_eq!(result.reason, StopReason::StepLimit);
+        let result = emu.run_watched(
+            Some(100),
+            Some(Watchdog {
+                window: 16,
+                steps: 10,
+            }),
+        );
+        assert_eq!(result.reason, StopReason::Stuck { start: 0, end: 0 });
+    }
+
+    /// `LDA [$7FFF]`, a word straddling the two segments, then `HALT`.
+    const STRADDLE: [u8; 4] = [0x10, 0xFF, 0x7F, 0xFF];
+
+    #[test]
+    fn split_words_run_on() {
+        let mut emu = split_ram(&STRADDLE, WordPolicy::Split);
+        assert_eq!(emu.run(None).reason, StopReason::Halt);
+    }
+
+    #[test]
+    fn faulting_words_stop_without_a_handler() {
+        let mut emu = split_ram(&STRADDLE, WordPolicy::Fault);
+        assert_eq!(emu.run(None).reason, StopReason::BusFault(0x7FFF));
+    }
+
+    #[test]
+    fn faulting_words_enter_the_handler() {
+        let mut emu = split_ram(&STRADDLE, WordPolicy::Fault);
+        emu.memory.write_word(BUS_FAULT_VECTOR as usize, 0x0100);
+        emu.memory.write_byte(0x0100, 0xD4);
+        assert_eq!(emu.run(None).reason, StopReason::Break);
+        // The handler returns to the faulting instruction.
+        assert_eq!(emu.memory.read_word(emu.sp as usize + 8 + 2), 0x0000);
+    }
+
+    #[test]
+    fn config_fits_the_stack_in_memory() {
+        let config = MachineConfig {
+            stack_top: 0x1000,
+            stack_size: 0x100,
+        };
+        let emu = Emulator::with_config([0; 0x1000], Ports::new(), config).unwrap();
+        assert_eq!(emu.sp, 0x1000);
+        let config = MachineConfig {
+            stack_top: 0,
+            stack_size: 0x100,
+        };
+        assert!(Emulator::with_config([0; MEM_SIZE], Ports::new(), config).is_ok());
+    }
+
+    #[test]
+    fn config_rejects_a_stack_outside_memory() {
+        let config = MachineConfig::default();
+        let error = ConfigError::StackOutsideMemory {
+            stack_top: STACK_TOP,
+            stack_size: 0x100,
+            len: 0x1000,
+        };
+        assert_eq!(
+            Emulator::with_config([0; 0x1000], Ports::new(), config).err(),
+            Some(error)
+        );
+        let config = MachineConfig {
+            stack_top: 0x0010,
+            stack_size: 0x100,
+        };
+        assert!(matches!(
+            Emulator::with_config([0; MEM_SIZE], Ports::new(), config),
+            Err(ConfigError::StackOutsideMemory { .. })
+        ));
+    }
+
+    #[test]
+    fn config_rejects_a_stack_in_rom() {
+        let mut memory = SegmentedMemory::new(MEM_SIZE);
+        memory.map(0, Box::new(vec![0; 0xEF80].into_boxed_slice()), false);
+        memory.map(0xEF80, Box::new(vec![0; 0x1080].into_boxed_slice()), true);
+        let error = ConfigError::StackNotWritable(0xEF80);
+        assert_eq!(
+            Emulator::with_config(memory, Ports::new(), MachineConfig::default()).err(),
+            Some(error)
+        );
+    }
+
+    /// An emulator with RAM below $8000, where the stack starts, and nothing mapped above.
+    fn ram_below_8000() -> Emulator<SegmentedMemory> {
+        let mut memory = SegmentedMemory::with_policy(MEM_SIZE, WordPolicy::Fault);
+        memory.map(0, Box::new(vec![0; 0x8000].into_boxed_slice()), false);
+        Emulator::with_stack(memory, 0x8000)
+    }
+
+    #[test]
+    fn fetching_from_unmapped_memory_faults_on_each_byte() {
+        // `NOP`, `LDI B, #$1234` and `CALLZ $1234`, cut off by unmapped memory at each byte.
+        for bytes in [&[0xD3][..], &[0x0D, 0x34, 0x12], &[0xB8, 0x00, 0x34, 0x12]] {
+            for cut in 0..bytes.len() {
+                let mut emu = ram_below_8000();
+                let start = 0x8000 - cut as u16;
+                emu.memory.write_array(start as usize, &bytes[..cut]);
+                emu.pc = start;
+                let result = emu.run(Some(1));
+                assert_eq!(
+                    (result.reason, result.pc),
+                    (StopReason::BusFault(0x8000), start),
+                    "{bytes:02X?} cut at {cut}"
+                );
+                assert_eq!((emu.b, emu.sp, result.cycles), (0, 0x8000, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn faulting_instructions_have_no_effect() {
+        // `LDA [$7FFF]` with A already loaded.
+        let mut emu = split_ram(&STRADDLE, WordPolicy::Fault);
+        emu.a = 0x5555;
+        assert_eq!(emu.run(None).reason, StopReason::BusFault(0x7FFF));
+        assert_eq!((emu.a, emu.pc), (0x5555, 0));
+
+        // `CALL $0100`, pushing the return address across the boundary.
+        let mut emu = split_ram(&[0x68, 0x00, 0x01], WordPolicy::Fault);
+        emu.sp = 0x8001;
+        assert_eq!(emu.run(None).reason, StopReason::BusFault(0x7FFF));
+        assert_eq!(
+            (emu.sp, emu.pc, emu.memory.read_word(0x7FFF)),
+            (0x8001, 0, 0)
+        );
+
+        // `PUSHA`, whose third word crosses the boundary. The words pushed before it are left below the stack pointer.
+        let mut emu = split_ram(&[0xA4], WordPolicy::Fault);
+        emu.sp = 0x8005;
+        assert_eq!(emu.run(None).reason, StopReason::BusFault(0x7FFF));
+        assert_eq!((emu.sp, emu.pc), (0x8005, 0));
+    }
+
+    /// An emulator running `program` at address 0, with an interrupt handler at $100 made of `handler`.
+    fn with_handler(program: &[u8], handler: &[u8]) -> Emulator {
+        let mut memory = [0; MEM_SIZE];
+        memory[..program.len()].copy_from_slice(program);
+        memory[0x100..0x100 + handler.len()].copy_from_slice(handler);
+        let mut emu = Emulator::new(memory);
+        emu.memory.write_word(0xFFFE, 0x100);
+        emu
+    }
+
+    #[test]
+    fn masked_interrupts_wait_for_their_line() {
+        // `NOP`, `NOP`, `HALT`, with a handler that only returns.
+        let mut emu = with_handler(&[0xD3, 0xD3, 0xFF], &[0xD2]);
+        emu.memory.write_word(INTERRUPT_MASK as usize, 1 << 3);
+        emu.interrupt(3);
+        emu.interrupt(0x16);
+        assert_eq!(emu.try_step(), Ok(Some(0x16)));
+        assert_eq!(emu.memory.read_word(0xFFFC), 0x16);
+        assert_eq!(emu.try_step(), Ok(None));
+        assert_eq!(emu.pc, 1);
+        emu.memory.write_word(INTERRUPT_MASK as usize, 0);
+        assert_eq!(emu.try_step(), Ok(Some(3)));
+        assert_eq!(emu.memory.read_word(0xFFFC), 3);
+    }
+
+    #[test]
+    fn interrupts_are_held_off_until_the_handler_returns() {
+        // `NOP`, `HALT`, with a handler of `NOP`, `RETI`.
+        let mut emu = with_handler(&[0xD3, 0xFF], &[0xD3, 0xD2]);
+        emu.interrupt(1);
+        assert_eq!(emu.try_step(), Ok(Some(1)));
+        emu.interrupt(2);
+        assert_eq!(emu.try_step(), Ok(None));
+        assert_eq!(emu.pc, 0x101);
+        // The second request is taken as soon as the first handler returns.
+        assert_eq!(emu.try_step(), Ok(Some(2)));
+        assert_eq!(emu.pc, 0x100);
+        assert_eq!(emu.memory.read_word(emu.sp as usize + 10), 1);
+        let result = emu.run(None);
+        assert_eq!((result.reason, result.pc), (StopReason::Halt, 2));
+    }
+
+    #[test]
+    fn only_unmasked_interrupts_wake_a_halted_processor() {
+        let mut emu = with_handler(&[0xFF], &[0xD2]);
+        assert_eq!(emu.run(None).reason, StopReason::Halt);
+        emu.memory.write_word(INTERRUPT_MASK as usize, 1 << 1);
+        emu.interrupt(1);
+        assert_eq!(emu.run(None).steps, 0);
+        emu.memory.write_word(INTERRUPT_MASK as usize, 0);
+        let result = emu.run(None);
+        assert_eq!(
+            (result.reason, result.steps, result.irq, result.pc),
+            (StopReason::Halt, 1, Some(1), 1)
+        );
+    }
+
+    #[test]
+    fn higher_priority_interrupts_nest() {
+        // `NOP`, `NOP`, `HALT`, with a handler of `NOP`, `NOP`, `RETI`.
+        let mut emu = with_handler(&[0xD3, 0xD3, 0xFF], &[0xD3, 0xD3, 0xD2]);
+        emu.interrupt(5);
+        assert_eq!(emu.try_step(), Ok(Some(5)));
+        assert_eq!(emu.flags.level(), 6);
+        // A lower-numbered line interrupts the handler.
+        emu.interrupt(2);
+        assert_eq!(emu.try_step(), Ok(Some(2)));
+        assert_eq!((emu.pc, emu.flags.level()), (0x100, 3));
+        // A higher-numbered one waits for every handler above it to return.
+        emu.interrupt(7);
+        assert_eq!(emu.try_step(), Ok(None));
+        assert_eq!(emu.try_step(), Ok(None));
+        assert_eq!(emu.try_step(), Ok(None));
+        assert_eq!((emu.pc, emu.flags.level()), (0x101, 6));
+        assert_eq!(emu.try_step(), Ok(None));
+        assert_eq!(emu.try_step(), Ok(Some(7)));
+        assert_eq!(emu.memory.read_word(emu.sp as usize + 10), 1);
+        assert_eq!(emu.flags.level(), 8);
+        let result = emu.run(None);
+        assert_eq!((result.reason, result.pc), (StopReason::Halt, 3));
+        assert_eq!((emu.flags.level(), emu.sp), (0, STACK_TOP));
+    }
+
+    #[test]
+    fn the_interrupt_flag_holds_off_every_line() {
+        // `SET I`, `NOP`, `CLR I`, `HALT`, with a handler that only returns.
+        let mut emu = with_handler(&[0xFE, 0xD3, 0xEE, 0xFF], &[0xD2]);
+        emu.interrupt(0);
+        assert_eq!(emu.try_step(), Ok(None));
+        assert_eq!(emu.try_step(), Ok(None));
+        assert_eq!(emu.try_step(), Ok(Some(0)));
+        assert!(!emu.flags.get(flag::INTERRUPT));
+    }
+
+    #[test]
+    fn privilege_faults_without_a_handler_stop() {
+        // `SET U`, `HALT`
+        let mut memory = [0; MEM_SIZE];
+        memory[..2].copy_from_slice(&[0xFC, 0xFF]);
+        let mut emu = Emulator::new(memory);
+        let result = emu.run(None);
+        assert_eq!(
+            (result.reason, result.steps, result.fault_address()),
+            (StopReason::PrivilegeFault, 1, Some(1))
+        );
+        assert!(emu.flags.get(flag::HALT) && emu.flags.get(flag::USER));
+    }
+
+    #[test]
+    fn brk_traps_in_user_mode() {
+        // `SET U`, `BRK`
+        let mut emu = ram_and_rom(&[0xFC, 0xD4]);
+        let result = emu.run(None);
+        assert_eq!((result.reason, result.pc), (StopReason::Halt, 0x8001));
+        assert!(!emu.flags.get(flag::BREAK));
+        assert_eq!(emu.memory.read_word(emu.sp as usize + 10), 2);
+    }
+
+    #[test]
+    fn a_watchdog_sees_loops_over_the_top_of_memory() {
+        // `NOP`, `NOP` at $FFFE, then `JR $FFFE` at $0000.
+        let mut memory = [0; MEM_SIZE];
+        memory[0xFFFE..].copy_from_slice(&[0xD3, 0xD3]);
+        memory[..3].copy_from_slice(&[0x62, 0xFB, 0xFF]);
+        let mut emu = Emulator::new(memory);
+        emu.pc = 0xFFFE;
+        let watchdog = Watchdog {
+            window: 16,
+            steps: 30,
+        };
+        let result = emu.run_watched(Some(100), Some(watchdog));
+        assert_eq!(
+            (result.reason, result.steps),
+            (
+                StopReason::Stuck {
+                    start: 0xFFFE,
+                    end: 0x0000
+                },
+                30
+            )
+        );
+    }
+}
@@ -2,5 +2,120 @@ pub const ZERO: u8 = 0;
 pub const SIGN: u8 = 1;
 pub const CARRY: u8 = 2;
 pub const OVERFLOW: u8 = 3;
+/// Lowest of the four flags holding the interrupt level: 0 outside any interrupt handler, or one more than the line
+/// whose handler is running. Requests on that line and higher-numbered ones wait until the handler returns.
+pub const LEVEL: u8 = 4;
+/// The flags holding the interrupt level.
+pub const LEVEL_FLAGS: u16 = 0xF << LEVEL;
+/// Set while running in user mode, where privileged instructions and writes to read-only memory fault. Cleared on
+/// entry to any interrupt.
+pub const USER: u8 = 12;
+/// Set by `Brk` together with [`HALT`] so a debugger can tell a breakpoint from a normal halt.
+pub const BREAK: u8 = 13;
+/// Set to hold off requests for hardware interrupts on every line, as around a critical section.
 pub const INTERRUPT: u8 = 14;
 pub const HALT: u8 = 15;
+
+/// Flags that user-mode code cannot change.
+pub const PRIVILEGED: u16 = 1 << USER | 1 << INTERRUPT | 1 << HALT | LEVEL_FLAGS;
+
+/// Names of the flags that have one, as the assembler's `SET` and `CLR` take them, indexed by flag number.
+const NAMES: [Option<&str>; 16] = [
+    Some("ZERO"),
+    Some("SIGN"),
+    Some("CARRY"),
+    Some("OVERFLOW"),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    Some("USER"),
+    Some("BREAK"),
+    Some("INTERRUPT"),
+    Some("HALT"),
+];
+
+/// Letters for [`Flags`]'s `Display`, from the highest flag to the lowest.
+const LETTERS: [(u8, char); 8] = [
+    (HALT, 'H'),
+    (INTERRUPT, 'I'),
+    (BREAK, 'B'),
+    (USER, 'U'),
+    (OVERFLOW, 'O'),
+    (CARRY, 'C'),
+    (SIGN, 'S'),
+    (ZERO, 'Z'),
+];
+
+/// The flags register, with one bit per flag numbered by the constants in this module.
+///
+/// Displays as the letter of each flag that is set, or `-`, from [`HALT`] down to [`ZERO`]: `---U-C-Z`.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Flags(pub u16);
+
+impl Flags {
+    /// Whether `flag` is set.
+    pub fn get(self, flag: u8) -> bool {
+        self.0 & (1 << flag) != 0
+    }
+
+    pub fn set(&mut self, flag: u8) {
+        self.0 |= 1 << flag;
+    }
+
+    pub fn clear(&mut self, flag: u8) {
+        self.0 &= !(1 << flag);
+    }
+
+    /// Set `flag` if `value` is true, and clear it otherwise.
+    pub fn assign(&mut self, flag: u8, value: bool) {
+        if value {
+            self.set(flag);
+        } else {
+            self.clear(flag);
+        }
+    }
+
+    pub fn toggle(&mut self, flag: u8) {
+        self.0 ^= 1 << flag;
+    }
+
+    /// The interrupt level held in the [`LEVEL_FLAGS`].
+    pub fn level(self) -> u16 {
+        (self.0 & LEVEL_FLAGS) >> LEVEL
+    }
+
+    pub fn set_level(&mut self, level: u16) {
+        self.0 = self.0 & !LEVEL_FLAGS | (level << LEVEL) & LEVEL_FLAGS;
+    }
+}
+
+/// Name of a flag, or `None` if it is reserved or out of range.
+pub fn name(flag: u8) -> Option<&'static str> {
+    NAMES.get(flag as usize).copied().flatten()
+}
+
+impl From<u16> for Flags {
+    fn from(value: u16) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Flags> for u16 {
+    fn from(value: Flags) -> Self {
+        value.0
+    }
+}
+
+impl std::fmt::Display for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (flag, letter) in LETTERS {
+            write!(f, "{}", if self.get(flag) { letter } else { '-' })?;
+        }
+        Ok(())
+    }
+}
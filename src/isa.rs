@@ -1,9 +1,65 @@
+use crate::assemble::{self, CompileError};
+use crate::condition;
 use crate::emulator::Emulator;
-use crate::flag;
+use crate::flag::{self, Flags};
 use crate::memory::Memory;
+use crate::port::PortDevice;
 use crate::register::GeneralPurposeRegister;
-use std::io::{Read, stdin};
 
+/// A single instruction.
+///
+/// Every instruction starts with a one-byte opcode, followed by a little-endian word or a single byte if it takes an
+/// operand. Opcode blocks of four select the register in their lowest two bits; conditional jumps and flag operations
+/// select the condition or flag in their lowest four bits, and `Int` selects the interrupt in its lowest three bits.
+/// `CallIf` and `ReturnIf` take their condition in the byte after the opcode.
+///
+/// | Opcode    | Instructions |
+/// |-----------|--------------|
+/// | `00`-`03` | `LoadFrom` |
+/// | `04`-`07` | `StoreTo` |
+/// | `08`-`0B` | `Zero` |
+/// | `0C`-`0F` | `LoadImmediate` |
+/// | `10`-`13` | `LoadAddress`, `LoadIndirect`, `LoadOffset`, `LoadStackOffset` |
+/// | `14`-`17` | `LoadByteAddress`, `LoadByteIndirect`, `LoadByteOffset`, `LoadByteStackOffset` |
+/// | `18`-`1B` | `StoreAddress`, `StoreIndirect`, `StoreOffset`, `StoreStackOffset` |
+/// | `1C`-`1F` | `StoreByteAddress`, `StoreByteIndirect`, `StoreByteOffset`, `StoreByteStackOffset` |
+/// | `20`-`23` | `Not` |
+/// | `24`-`27` | `SignExtendByte` |
+/// | `28`-`2B` | `Increment` |
+/// | `2C`-`2F` | `Decrement` |
+/// | `30`-`33` | `And` |
+/// | `34`-`37` | `Or` |
+/// | `38`-`3B` | `Xor` |
+/// | `3C`-`3F` | `LeftShift` |
+/// | `40`-`43` | `RightShift` |
+/// | `44`-`47` | `Add` |
+/// | `48`-`4B` | `Subtract` |
+/// | `4C`-`4F` | `AddWithCarry` |
+/// | `50`-`53` | `SubtractWithBorrow` |
+/// | `54`-`57` | `CompareA` |
+/// | `58`-`5B` | `CompareImmediate` |
+/// | `5C`-`5F` | `ZeroExtendByte` |
+/// | `60`-`62` | `Jump`, `JumpOffset`, `JumpRelative` |
+/// | `64`-`67` | `Loop`, `LoopOffset`, `LoopRelative`, `LoopNotZero` |
+/// | `68`-`6A` | `Call`, `CallOffset`, `CallRelative` |
+/// | `70`-`7F` | `JumpIf` |
+/// | `80`-`8F` | `JumpOffsetIf` |
+/// | `90`-`9F` | `JumpRelativeIf` |
+/// | `A0`-`A4` | `Push`, `PushPC`, `PushFlags`, `Enter`, `PushAll` |
+/// | `A8`-`AD` | `Pop`, `Return`, `PopFlags`, `ReturnDeallocate`, `Leave`, `PopAll` |
+/// | `B0`-`B1` | `Input`, `Output` |
+/// | `B4`-`B7` | `SwapBytes` |
+/// | `B8`-`B9` | `CallIf`, `ReturnIf` |
+/// | `BC`-`BF` | `LoadIndirectPostInc`, `LoadByteIndirectPostInc`, `StoreIndirectPostInc`, `StoreByteIndirectPostInc` |
+/// | `C0`-`C3` | `LeftShiftImmediate`, `RightShiftImmediate`, `RotateLeftImmediate`, `RotateRightImmediate` |
+/// | `C4`-`C8` | `AddImmediate`, `SubtractImmediate`, `AndImmediate`, `OrImmediate`, `XorImmediate` |
+/// | `D0`-`D2` | `SetInterrupt`, `CallInterrupt`, `ReturnInterrupt` |
+/// | `D3`-`D4` | `Nop`, `Brk` |
+/// | `D8`-`DF` | `Int` |
+/// | `E0`-`EF` | `Clear` |
+/// | `F0`-`FF` | `Set` |
+///
+/// All other opcodes are invalid and decode to [`InstructionError::InvalidOpcode`].
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub enum Instruction {
     /// Load the value of the given register into the accumulator.
@@ -52,12 +108,27 @@ pub enum Instruction {
     /// Store the value of the accumulator to the given address relative to the stack pointer.
     StoreStackOffset(u16),
 
+    /// Load the word at the address in the base register into the accumulator, then advance the base register by 2.
+    LoadIndirectPostInc,
+    /// Load the byte at the address in the base register into the accumulator, then advance the base register by 1.
+    LoadByteIndirectPostInc,
+    /// Store the value of the accumulator to the address in the base register, then advance the base register by 2.
+    StoreIndirectPostInc,
+    /// Store the lower byte of the accumulator to the address in the base register, then advance the base register by 1.
+    StoreByteIndirectPostInc,
+
     /// Invert the given register.
     Not(GeneralPurposeRegister),
     /// Increment the given register.
     Increment(GeneralPurposeRegister),
     /// Decrement the given register.
     Decrement(GeneralPurposeRegister),
+    /// Sign-extend the lower byte of the given register to a word.
+    SignExtendByte(GeneralPurposeRegister),
+    /// Zero-extend the lower byte of the given register to a word.
+    ZeroExtendByte(GeneralPurposeRegister),
+    /// Exchange the upper and lower bytes of the given register.
+    SwapBytes(GeneralPurposeRegister),
 
     /// Bitwise AND the accumulator with the given register.
     And(GeneralPurposeRegister),
@@ -69,6 +140,14 @@ pub enum Instruction {
     LeftShift(GeneralPurposeRegister),
     /// Right shift the accumulator by the given register.
     RightShift(GeneralPurposeRegister),
+    /// Left shift the accumulator by the given amount.
+    LeftShiftImmediate(u8),
+    /// Right shift the accumulator by the given amount.
+    RightShiftImmediate(u8),
+    /// Rotate the accumulator left by the given amount.
+    RotateLeftImmediate(u8),
+    /// Rotate the accumulator right by the given amount.
+    RotateRightImmediate(u8),
     /// Add the given register to the accumulator.
     Add(GeneralPurposeRegister),
     /// Subtract the given register from the accumulator.
@@ -77,6 +156,16 @@ pub enum Instruction {
     AddWithCarry(GeneralPurposeRegister),
     /// Subtract the given register from the accumulator with the carry flag.
     SubtractWithBorrow(GeneralPurposeRegister),
+    /// Add the given immediate value to the accumulator.
+    AddImmediate(u16),
+    /// Subtract the given immediate value from the accumulator.
+    SubtractImmediate(u16),
+    /// Bitwise AND the accumulator with the given immediate value.
+    AndImmediate(u16),
+    /// Bitwise OR the accumulator with the given immediate value.
+    OrImmediate(u16),
+    /// Bitwise XOR the accumulator with the given immediate value.
+    XorImmediate(u16),
 
     /// Compare the accumulator with the given register.
     CompareA(GeneralPurposeRegister),
@@ -103,6 +192,8 @@ pub enum Instruction {
     LoopOffset(u16),
     /// Decrement the counter register and jump to the given address relative to the next instruction if the counter register is not zero.
     LoopRelative(u16),
+    /// Decrement the counter register and jump by the given signed offset from the next instruction if the counter register is not zero.
+    LoopNotZero(i8),
 
     /// Call a subroutine at the given address.
     Call(u16),
@@ -120,33 +211,75 @@ pub enum Instruction {
     PushPC,
     /// Pop the program counter from the stack. This is used to return from a subroutine.
     Return,
+    /// Pop the program counter from the stack, then remove the given number of bytes of arguments from the stack.
+    ReturnDeallocate(u8),
+    /// Call a subroutine at the given address if the condition is met.
+    CallIf(u8, u16),
+    /// Return from a subroutine if the condition is met.
+    ReturnIf(u8),
 
     /// Push the flags onto the stack.
     PushFlags,
-    /// Pop the flags from the stack.
+    /// Pop the flags from the stack. In user mode, the privileged flags keep their values.
     PopFlags,
 
-    /// Call an interrupt by pushing the program counter, flags, and registers onto the stack. Sets the source of the interrupt to the data register.
+    /// Push the accumulator, base, counter, and data registers onto the stack, in that order.
+    PushAll,
+    /// Pop the data, counter, base, and accumulator registers from the stack, undoing `PushAll`.
+    PopAll,
+
+    /// Set up a stack frame: push the base register, point it at the stack, and reserve the given number of bytes below it.
+    Enter(u16),
+    /// Tear down the stack frame set up by `Enter`, restoring the stack pointer and base register.
+    Leave,
+
+    /// Request a hardware interrupt from the source in the data register, as `Emulator::interrupt` does. It is taken by pushing the program counter, flags, and registers onto the stack.
     CallInterrupt,
     /// Return from an interrupt by popping the program counter, flags, and registers from the stack.
     ReturnInterrupt,
+    /// Call the given software interrupt (0-7) by pushing the program counter, flags, and registers onto the stack and jumping through the software interrupt vector table.
+    Int(u8),
 
-    /// Read the port specified by the data register into the accumulator.
+    /// Read the port specified by the lower byte of the data register into the accumulator.
     Input,
-    /// Write the accumulator to the port specified by the data register.
+    /// Write the accumulator to the port specified by the lower byte of the data register.
     Output,
 
+    /// Do nothing.
+    Nop,
+    /// Stop at a breakpoint by setting the break and halt flags. Privileged, since it halts.
+    Brk,
+
     /// Set the interrupt vector to the given address.
     SetInterrupt(u16),
-    /// Clear the given flag.
+    /// Clear the given flag (0-15).
     Clear(u8),
-    /// Set the given flag.
+    /// Set the given flag (0-15).
     Set(u8),
 }
 
+/// Encode an instruction.
+///
+/// # Panics
+///
+/// Panics if a condition or flag does not fit in 4 bits, or an interrupt number in 3, since those are packed into the
+/// opcode and a larger one would encode a different instruction.
 impl From<Instruction> for Vec<u8> {
     fn from(value: Instruction) -> Self {
         use Instruction::*;
+        match value {
+            JumpIf(cond, _) | JumpOffsetIf(cond, _) | JumpRelativeIf(cond, _) => {
+                assert!(cond < 0x10, "condition {cond} does not fit in the opcode")
+            }
+            CallIf(cond, _) | ReturnIf(cond) => {
+                assert!(cond < 0x10, "condition {cond} is not a valid condition")
+            }
+            Clear(flag) | Set(flag) => {
+                assert!(flag < 0x10, "flag {flag} does not fit in the opcode")
+            }
+            Int(number) => assert!(number < 8, "interrupt {number} does not fit in the opcode"),
+            _ => {}
+        }
         match value {
             LoadFrom(reg) => vec![(reg as u8)],
             StoreTo(reg) => vec![0x04 | reg as u8],
@@ -173,14 +306,31 @@ impl From<Instruction> for Vec<u8> {
             StoreByteOffset(offset) => vec![0x1E, offset as u8, (offset >> 8) as u8],
             StoreByteStackOffset(offset) => vec![0x1F, offset as u8, (offset >> 8) as u8],
 
+            LoadIndirectPostInc => vec![0xBC],
+            LoadByteIndirectPostInc => vec![0xBD],
+            StoreIndirectPostInc => vec![0xBE],
+            StoreByteIndirectPostInc => vec![0xBF],
+
             Not(reg) => vec![0x20 | reg as u8],
             Increment(reg) => vec![0x28 | reg as u8],
             Decrement(reg) => vec![0x2C | reg as u8],
+            SignExtendByte(reg) => vec![0x24 | reg as u8],
+            ZeroExtendByte(reg) => vec![0x5C | reg as u8],
+            SwapBytes(reg) => vec![0xB4 | reg as u8],
             And(reg) => vec![0x30 | reg as u8],
             Or(reg) => vec![0x34 | reg as u8],
             Xor(reg) => vec![0x38 | reg as u8],
             LeftShift(reg) => vec![0x3C | reg as u8],
             RightShift(reg) => vec![0x40 | reg as u8],
+            LeftShiftImmediate(amount) => vec![0xC0, amount],
+            RightShiftImmediate(amount) => vec![0xC1, amount],
+            RotateLeftImmediate(amount) => vec![0xC2, amount],
+            RotateRightImmediate(amount) => vec![0xC3, amount],
+            AddImmediate(value) => vec![0xC4, value as u8, (value >> 8) as u8],
+            SubtractImmediate(value) => vec![0xC5, value as u8, (value >> 8) as u8],
+            AndImmediate(value) => vec![0xC6, value as u8, (value >> 8) as u8],
+            OrImmediate(value) => vec![0xC7, value as u8, (value >> 8) as u8],
+            XorImmediate(value) => vec![0xC8, value as u8, (value >> 8) as u8],
             Add(reg) => vec![0x44 | reg as u8],
             Subtract(reg) => vec![0x48 | reg as u8],
             AddWithCarry(reg) => vec![0x4C | reg as u8],
@@ -195,6 +345,7 @@ impl From<Instruction> for Vec<u8> {
             Loop(address) => vec![0x64, address as u8, (address >> 8) as u8],
             LoopOffset(offset) => vec![0x65, offset as u8, (offset >> 8) as u8],
             LoopRelative(offset) => vec![0x66, offset as u8, (offset >> 8) as u8],
+            LoopNotZero(offset) => vec![0x67, offset as u8],
             Call(address) => vec![0x68, address as u8, (address >> 8) as u8],
             CallOffset(offset) => vec![0x69, offset as u8, (offset >> 8) as u8],
             CallRelative(offset) => vec![0x6A, offset as u8, (offset >> 8) as u8],
@@ -214,13 +365,23 @@ impl From<Instruction> for Vec<u8> {
             Pop => vec![0xA8],
             Return => vec![0xA9],
             PopFlags => vec![0xAA],
+            ReturnDeallocate(size) => vec![0xAB, size],
+            CallIf(cond, address) => vec![0xB8, cond, address as u8, (address >> 8) as u8],
+            ReturnIf(cond) => vec![0xB9, cond],
+            Enter(size) => vec![0xA3, size as u8, (size >> 8) as u8],
+            Leave => vec![0xAC],
+            PushAll => vec![0xA4],
+            PopAll => vec![0xAD],
 
             Input => vec![0xB0],
             Output => vec![0xB1],
 
+            Nop => vec![0xD3],
+            Brk => vec![0xD4],
             SetInterrupt(address) => vec![0xD0, address as u8, (address >> 8) as u8],
             CallInterrupt => vec![0xD1],
             ReturnInterrupt => vec![0xD2],
+            Int(number) => vec![0xD8 | number],
             Clear(flag) => vec![0xE0 | flag],
             Set(flag) => vec![0xF0 | flag],
         }
@@ -233,7 +394,189 @@ impl From<&Instruction> for Vec<u8> {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Formats the instruction in the syntax the assembler accepts, so the output assembles back to the same bytes.
+///
+/// Words are written in hexadecimal (`LDI B, #$C000`), and byte counts and shift amounts in decimal. The assembler
+/// takes the operand of a relative jump as its target, so the target is written relative to the start of the
+/// instruction, as in `JR $+8`, which does not depend on where the instruction is. Conditions and flags are written by
+/// name where they have one, and by number otherwise (`JMPIF 4, $0100`).
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Instruction::*;
+
+        /// A conditional mnemonic: `J` with `NZ` as `JNZ`, or `JMPIF 4, ` for a condition without a name.
+        fn conditional(
+            f: &mut std::fmt::Formatter<'_>,
+            prefix: &str,
+            explicit: &str,
+            cond: u8,
+        ) -> std::fmt::Result {
+            match condition::name(cond) {
+                Some(name) => write!(f, "{prefix}{name} "),
+                None => write!(f, "{explicit} {cond}, "),
+            }
+        }
+
+        /// The target of a relative branch, `offset` bytes past the end of the `length`-byte instruction, written
+        /// relative to its start.
+        fn target(f: &mut std::fmt::Formatter<'_>, offset: i16, length: i32) -> std::fmt::Result {
+            match offset as i32 + length {
+                0 => write!(f, "$"),
+                distance if distance < 0 => write!(f, "$-{}", -distance),
+                distance => write!(f, "$+{distance}"),
+            }
+        }
+
+        let length = Vec::from(*self).len() as i32;
+        match *self {
+            LoadFrom(reg) => write!(f, "LDA {reg:?}"),
+            StoreTo(reg) => write!(f, "STA {reg:?}"),
+            Zero(reg) => write!(f, "ZERO {reg:?}"),
+            LoadImmediate(reg, value) => write!(f, "LDI {reg:?}, #${value:04X}"),
+
+            LoadAddress(address) => write!(f, "LDA [${address:04X}]"),
+            LoadIndirect => write!(f, "LDA [B]"),
+            LoadOffset(offset) => write!(f, "LDA [B+${offset:04X}]"),
+            LoadStackOffset(offset) => write!(f, "LDA [SP+${offset:04X}]"),
+
+            LoadByteAddress(address) => write!(f, "LDAB [${address:04X}]"),
+            LoadByteIndirect => write!(f, "LDAB [B]"),
+            LoadByteOffset(offset) => write!(f, "LDAB [B+${offset:04X}]"),
+            LoadByteStackOffset(offset) => write!(f, "LDAB [SP+${offset:04X}]"),
+
+            StoreAddress(address) => write!(f, "STA [${address:04X}]"),
+            StoreIndirect => write!(f, "STA [B]"),
+            StoreOffset(offset) => write!(f, "STA [B+${offset:04X}]"),
+            StoreStackOffset(offset) => write!(f, "STA [SP+${offset:04X}]"),
+
+            StoreByteAddress(address) => write!(f, "STAB [${address:04X}]"),
+            StoreByteIndirect => write!(f, "STAB [B]"),
+            StoreByteOffset(offset) => write!(f, "STAB [B+${offset:04X}]"),
+            StoreByteStackOffset(offset) => write!(f, "STAB [SP+${offset:04X}]"),
+
+            LoadIndirectPostInc => write!(f, "LDA [B+]"),
+            LoadByteIndirectPostInc => write!(f, "LDAB [B+]"),
+            StoreIndirectPostInc => write!(f, "STA [B+]"),
+            StoreByteIndirectPostInc => write!(f, "STAB [B+]"),
+
+            Not(reg) => write!(f, "NOT {reg:?}"),
+            Increment(reg) => write!(f, "INC {reg:?}"),
+            Decrement(reg) => write!(f, "DEC {reg:?}"),
+            SignExtendByte(reg) => write!(f, "SEXT {reg:?}"),
+            ZeroExtendByte(reg) => write!(f, "ZEXT {reg:?}"),
+            SwapBytes(reg) => write!(f, "SWAP {reg:?}"),
+            And(reg) => write!(f, "AND {reg:?}"),
+            Or(reg) => write!(f, "OR {reg:?}"),
+            Xor(reg) => write!(f, "XOR {reg:?}"),
+            LeftShift(reg) => write!(f, "SHL {reg:?}"),
+            RightShift(reg) => write!(f, "SHR {reg:?}"),
+            LeftShiftImmediate(amount) => write!(f, "SHL #{amount}"),
+            RightShiftImmediate(amount) => write!(f, "SHR #{amount}"),
+            RotateLeftImmediate(amount) => write!(f, "ROL #{amount}"),
+            RotateRightImmediate(amount) => write!(f, "ROR #{amount}"),
+            Add(reg) => write!(f, "ADD {reg:?}"),
+            Subtract(reg) => write!(f, "SUB {reg:?}"),
+            AddWithCarry(reg) => write!(f, "ADC {reg:?}"),
+            SubtractWithBorrow(reg) => write!(f, "SBB {reg:?}"),
+            AddImmediate(value) => write!(f, "ADDI #${value:04X}"),
+            SubtractImmediate(value) => write!(f, "SUBI #${value:04X}"),
+            AndImmediate(value) => write!(f, "ANDI #${value:04X}"),
+            OrImmediate(value) => write!(f, "ORI #${value:04X}"),
+            XorImmediate(value) => write!(f, "XORI #${value:04X}"),
+
+            CompareA(reg) => write!(f, "CMP {reg:?}"),
+            CompareImmediate(reg, value) => write!(f, "CMPI {reg:?}, #${value:04X}"),
+
+            Jump(address) => write!(f, "JMP ${address:04X}"),
+            JumpOffset(offset) => write!(f, "JMPX ${offset:04X}"),
+            JumpRelative(offset) => {
+                write!(f, "JR ")?;
+                target(f, offset as i16, length)
+            }
+            JumpIf(cond, address) => {
+                conditional(f, "J", "JMPIF", cond)?;
+                write!(f, "${address:04X}")
+            }
+            JumpOffsetIf(cond, offset) => {
+                conditional(f, "JX", "JXIF", cond)?;
+                write!(f, "${offset:04X}")
+            }
+            JumpRelativeIf(cond, offset) => {
+                conditional(f, "JR", "JRIF", cond)?;
+                target(f, offset as i16, length)
+            }
+            Loop(address) => write!(f, "LOOP ${address:04X}"),
+            LoopOffset(offset) => write!(f, "LOOPX ${offset:04X}"),
+            LoopRelative(offset) => {
+                write!(f, "LOOPR ")?;
+                target(f, offset as i16, length)
+            }
+            LoopNotZero(offset) => {
+                write!(f, "DJNZ ")?;
+                target(f, offset as i16, length)
+            }
+            Call(address) => write!(f, "CALL ${address:04X}"),
+            CallOffset(offset) => write!(f, "CALLX ${offset:04X}"),
+            CallRelative(offset) => {
+                write!(f, "CALLR ")?;
+                target(f, offset as i16, length)
+            }
+            CallIf(cond, address) => {
+                conditional(f, "CALL", "CALLIF", cond)?;
+                write!(f, "${address:04X}")
+            }
+
+            Push => write!(f, "PUSH"),
+            Pop => write!(f, "POP"),
+            PushPC => write!(f, "PUSHPC"),
+            Return => write!(f, "RET"),
+            ReturnDeallocate(size) => write!(f, "RET #{size}"),
+            ReturnIf(cond) => match condition::name(cond) {
+                Some(name) => write!(f, "RET{name}"),
+                None => write!(f, "RETIF {cond}"),
+            },
+            PushFlags => write!(f, "PUSHF"),
+            PopFlags => write!(f, "POPF"),
+            PushAll => write!(f, "PUSHA"),
+            PopAll => write!(f, "POPA"),
+            Enter(size) => write!(f, "ENTER #{size}"),
+            Leave => write!(f, "LEAVE"),
+
+            CallInterrupt => write!(f, "INT"),
+            ReturnInterrupt => write!(f, "RETI"),
+            Int(number) => write!(f, "INT {number}"),
+
+            Input => write!(f, "IN"),
+            Output => write!(f, "OUT"),
+
+            Nop => write!(f, "NOP"),
+            Brk => write!(f, "BRK"),
+
+            SetInterrupt(address) => write!(f, "SETINT ${address:04X}"),
+            Set(flag::HALT) => write!(f, "HALT"),
+            Clear(flag) | Set(flag) => {
+                let mnemonic = if matches!(self, Set(_)) { "SET" } else { "CLR" };
+                match flag::name(flag) {
+                    Some(name) => write!(f, "{mnemonic} {name}"),
+                    None => write!(f, "{mnemonic} {flag}"),
+                }
+            }
+        }
+    }
+}
+
+/// Parses a single statement in assembler syntax, such as `LDI B, #$C000`, the inverse of `Display`.
+///
+/// Operands can be numbers or expressions of numbers, but not symbols.
+impl std::str::FromStr for Instruction {
+    type Err = CompileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        assemble::parse_statement(s)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub enum InstructionError {
     InvalidOpcode(u8),
     EndOfInput,
@@ -297,7 +640,12 @@ impl Instruction {
             0x1D => StoreByteIndirect,
             0x1E => StoreByteOffset(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0x1F => StoreByteStackOffset(u16::from_le_bytes([next_byte()?, next_byte()?])),
+            0xBC => LoadIndirectPostInc,
+            0xBD => LoadByteIndirectPostInc,
+            0xBE => StoreIndirectPostInc,
+            0xBF => StoreByteIndirectPostInc,
             0x20..=0x23 => Not(register),
+            0x24..=0x27 => SignExtendByte(register),
             0x28..=0x2B => Increment(register),
             0x2C..=0x2F => Decrement(register),
             0x30..=0x33 => And(register),
@@ -313,12 +661,14 @@ impl Instruction {
             0x58..=0x5B => {
                 CompareImmediate(register, u16::from_le_bytes([next_byte()?, next_byte()?]))
             }
+            0x5C..=0x5F => ZeroExtendByte(register),
             0x60 => Jump(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0x61 => JumpOffset(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0x62 => JumpRelative(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0x64 => Loop(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0x65 => LoopOffset(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0x66 => LoopRelative(u16::from_le_bytes([next_byte()?, next_byte()?])),
+            0x67 => LoopNotZero(next_byte()? as i8),
             0x68 => Call(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0x69 => CallOffset(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0x6A => CallRelative(u16::from_le_bytes([next_byte()?, next_byte()?])),
@@ -340,11 +690,37 @@ impl Instruction {
             0xA8 => Pop,
             0xA9 => Return,
             0xAA => PopFlags,
+            0xAB => ReturnDeallocate(next_byte()?),
+            0xA3 => Enter(u16::from_le_bytes([next_byte()?, next_byte()?])),
+            0xAC => Leave,
+            0xA4 => PushAll,
+            0xAD => PopAll,
             0xB0 => Input,
             0xB1 => Output,
+            0xB4..=0xB7 => SwapBytes(register),
+            0xB8 => match next_byte()? {
+                cond @ 0x0..=0xF => CallIf(cond, u16::from_le_bytes([next_byte()?, next_byte()?])),
+                _ => return Err(InstructionError::InvalidOpcode(opcode)),
+            },
+            0xB9 => match next_byte()? {
+                cond @ 0x0..=0xF => ReturnIf(cond),
+                _ => return Err(InstructionError::InvalidOpcode(opcode)),
+            },
+            0xC0 => LeftShiftImmediate(next_byte()?),
+            0xC1 => RightShiftImmediate(next_byte()?),
+            0xC2 => RotateLeftImmediate(next_byte()?),
+            0xC3 => RotateRightImmediate(next_byte()?),
+            0xC4 => AddImmediate(u16::from_le_bytes([next_byte()?, next_byte()?])),
+            0xC5 => SubtractImmediate(u16::from_le_bytes([next_byte()?, next_byte()?])),
+            0xC6 => AndImmediate(u16::from_le_bytes([next_byte()?, next_byte()?])),
+            0xC7 => OrImmediate(u16::from_le_bytes([next_byte()?, next_byte()?])),
+            0xC8 => XorImmediate(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0xD0 => SetInterrupt(u16::from_le_bytes([next_byte()?, next_byte()?])),
             0xD1 => CallInterrupt,
             0xD2 => ReturnInterrupt,
+            0xD3 => Nop,
+            0xD4 => Brk,
+            0xD8..=0xDF => Int(opcode & 0x7),
             0xE0..=0xEF => Clear(opcode & 0xF),
             0xF0..=0xFF => Set(opcode & 0xF),
 
@@ -354,8 +730,276 @@ impl Instruction {
     }
 }
 
-impl<M: Memory> Emulator<M> {
+impl Instruction {
+    /// Whether this instruction may transfer control somewhere other than the next instruction.
+    pub fn is_branch(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            Jump(_)
+                | JumpOffset(_)
+                | JumpRelative(_)
+                | JumpIf(..)
+                | JumpOffsetIf(..)
+                | JumpRelativeIf(..)
+                | Loop(_)
+                | LoopOffset(_)
+                | LoopRelative(_)
+                | LoopNotZero(_)
+                | Call(_)
+                | CallOffset(_)
+                | CallRelative(_)
+                | CallIf(..)
+                | Return
+                | ReturnDeallocate(_)
+                | ReturnIf(_)
+                | CallInterrupt
+                | ReturnInterrupt
+                | Int(_)
+        )
+    }
+
+    /// Whether this instruction calls a subroutine that is expected to return to the next instruction.
+    pub fn is_call(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            Call(_) | CallOffset(_) | CallRelative(_) | CallIf(..) | CallInterrupt | Int(_)
+        )
+    }
+
+    /// Whether this instruction returns from a subroutine or interrupt.
+    pub fn is_return(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Return
+                | Instruction::ReturnDeallocate(_)
+                | Instruction::ReturnIf(_)
+                | Instruction::ReturnInterrupt
+        )
+    }
+
+    /// The address this instruction branches to, if it is known without running it.
+    ///
+    /// `next` is the address of the following instruction, which relative branches are measured from.
+    pub fn branch_target(&self, next: u16) -> Option<u16> {
+        use Instruction::*;
+        match *self {
+            Jump(address)
+            | JumpIf(_, address)
+            | Loop(address)
+            | Call(address)
+            | CallIf(_, address) => Some(address),
+            JumpRelative(offset)
+            | JumpRelativeIf(_, offset)
+            | LoopRelative(offset)
+            | CallRelative(offset) => Some(next.wrapping_add(offset)),
+            LoopNotZero(offset) => Some(next.wrapping_add_signed(offset as i16)),
+            _ => None,
+        }
+    }
+
+    /// The general purpose registers this instruction reads.
+    pub fn reads(&self) -> Vec<GeneralPurposeRegister> {
+        use GeneralPurposeRegister::*;
+        use Instruction::*;
+        match *self {
+            LoadFrom(reg) => vec![reg],
+            StoreTo(_) => vec![A],
+            Zero(_) | LoadImmediate(..) => vec![],
+            LoadAddress(_) | LoadStackOffset(_) | LoadByteAddress(_) | LoadByteStackOffset(_) => {
+                vec![]
+            }
+            LoadIndirect | LoadOffset(_) | LoadByteIndirect | LoadByteOffset(_) => vec![B],
+            StoreAddress(_)
+            | StoreStackOffset(_)
+            | StoreByteAddress(_)
+            | StoreByteStackOffset(_) => {
+                vec![A]
+            }
+            StoreIndirect | StoreOffset(_) | StoreByteIndirect | StoreByteOffset(_) => vec![A, B],
+            LoadIndirectPostInc | LoadByteIndirectPostInc => vec![B],
+            StoreIndirectPostInc | StoreByteIndirectPostInc => vec![A, B],
+            Not(reg) | Increment(reg) | Decrement(reg) => vec![reg],
+            SignExtendByte(reg) | ZeroExtendByte(reg) | SwapBytes(reg) => vec![reg],
+            And(reg) | Or(reg) | Xor(reg) | LeftShift(reg) | RightShift(reg) => vec![A, reg],
+            Add(reg) | Subtract(reg) | AddWithCarry(reg) | SubtractWithBorrow(reg) => vec![A, reg],
+            LeftShiftImmediate(_)
+            | RightShiftImmediate(_)
+            | RotateLeftImmediate(_)
+            | RotateRightImmediate(_) => vec![A],
+            AddImmediate(_) | SubtractImmediate(_) | AndImmediate(_) | OrImmediate(_)
+            | XorImmediate(_) => {
+                vec![A]
+            }
+            CompareA(reg) => vec![A, reg],
+            CompareImmediate(reg, _) => vec![reg],
+            Jump(_) | JumpRelative(_) | JumpIf(..) | JumpRelativeIf(..) => vec![],
+            JumpOffset(_) | JumpOffsetIf(..) => vec![B],
+            Loop(_) | LoopRelative(_) | LoopNotZero(_) => vec![C],
+            LoopOffset(_) => vec![B, C],
+            Call(_) | CallRelative(_) => vec![],
+            CallOffset(_) => vec![B],
+            Push => vec![A],
+            Pop | PushPC | Return | ReturnDeallocate(_) | PushFlags | PopFlags => vec![],
+            CallIf(..) | ReturnIf(_) => vec![],
+            Enter(_) | Leave => vec![B],
+            PushAll => vec![A, B, C, D],
+            PopAll => vec![],
+            CallInterrupt => vec![D],
+            ReturnInterrupt => vec![],
+            Int(_) => vec![A, B, C, D],
+            Input => vec![D],
+            Output => vec![A, D],
+            Nop | Brk | SetInterrupt(_) | Clear(_) | Set(_) => vec![],
+        }
+    }
+
+    /// The general purpose registers this instruction writes.
+    pub fn writes(&self) -> Vec<GeneralPurposeRegister> {
+        use GeneralPurposeRegister::*;
+        use Instruction::*;
+        match *self {
+            LoadFrom(_) => vec![A],
+            StoreTo(reg) | Zero(reg) | LoadImmediate(reg, _) => vec![reg],
+            LoadAddress(_) | LoadIndirect | LoadOffset(_) | LoadStackOffset(_) => vec![A],
+            LoadByteAddress(_) | LoadByteIndirect | LoadByteOffset(_) | LoadByteStackOffset(_) => {
+                vec![A]
+            }
+            StoreAddress(_) | StoreIndirect | StoreOffset(_) | StoreStackOffset(_) => vec![],
+            StoreByteAddress(_)
+            | StoreByteIndirect
+            | StoreByteOffset(_)
+            | StoreByteStackOffset(_) => vec![],
+            LoadIndirectPostInc | LoadByteIndirectPostInc => vec![A, B],
+            StoreIndirectPostInc | StoreByteIndirectPostInc => vec![B],
+            Not(reg) | Increment(reg) | Decrement(reg) => vec![reg],
+            SignExtendByte(reg) | ZeroExtendByte(reg) | SwapBytes(reg) => vec![reg],
+            And(_) | Or(_) | Xor(_) | LeftShift(_) | RightShift(_) => vec![A],
+            Add(_) | Subtract(_) | AddWithCarry(_) | SubtractWithBorrow(_) => vec![A],
+            LeftShiftImmediate(_)
+            | RightShiftImmediate(_)
+            | RotateLeftImmediate(_)
+            | RotateRightImmediate(_) => vec![A],
+            AddImmediate(_) | SubtractImmediate(_) | AndImmediate(_) | OrImmediate(_)
+            | XorImmediate(_) => {
+                vec![A]
+            }
+            CompareA(_) | CompareImmediate(..) => vec![],
+            Jump(_) | JumpOffset(_) | JumpRelative(_) => vec![],
+            JumpIf(..) | JumpOffsetIf(..) | JumpRelativeIf(..) => vec![],
+            Loop(_) | LoopOffset(_) | LoopRelative(_) | LoopNotZero(_) => vec![C],
+            Call(_) | CallOffset(_) | CallRelative(_) => vec![],
+            Push | PushPC | Return | ReturnDeallocate(_) | PushFlags | PopFlags => vec![],
+            CallIf(..) | ReturnIf(_) => vec![],
+            Enter(_) | Leave => vec![B],
+            Pop => vec![A],
+            PushAll => vec![],
+            PopAll => vec![A, B, C, D],
+            CallInterrupt | Int(_) => vec![],
+            ReturnInterrupt => vec![A, B, C, D],
+            Input => vec![A],
+            Output => vec![],
+            Nop | Brk | SetInterrupt(_) | Clear(_) | Set(_) => vec![],
+        }
+    }
+
+    /// Whether this instruction reads or writes memory, including the stack and the interrupt vector.
+    pub fn accesses_memory(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            LoadAddress(_)
+                | LoadIndirect
+                | LoadOffset(_)
+                | LoadStackOffset(_)
+                | LoadByteAddress(_)
+                | LoadByteIndirect
+                | LoadByteOffset(_)
+                | LoadByteStackOffset(_)
+                | StoreAddress(_)
+                | StoreIndirect
+                | StoreOffset(_)
+                | StoreStackOffset(_)
+                | StoreByteAddress(_)
+                | StoreByteIndirect
+                | StoreByteOffset(_)
+                | StoreByteStackOffset(_)
+                | LoadIndirectPostInc
+                | LoadByteIndirectPostInc
+                | StoreIndirectPostInc
+                | StoreByteIndirectPostInc
+                | Call(_)
+                | CallOffset(_)
+                | CallRelative(_)
+                | Push
+                | Pop
+                | PushPC
+                | Return
+                | ReturnDeallocate(_)
+                | CallIf(..)
+                | ReturnIf(_)
+                | PushFlags
+                | PopFlags
+                | PushAll
+                | PopAll
+                | Enter(_)
+                | Leave
+                | CallInterrupt
+                | ReturnInterrupt
+                | Int(_)
+                | SetInterrupt(_)
+        )
+    }
+
+    /// Whether this instruction writes memory, including pushing to the stack and setting the interrupt vector.
+    pub fn writes_memory(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            StoreAddress(_)
+                | StoreIndirect
+                | StoreOffset(_)
+                | StoreStackOffset(_)
+                | StoreByteAddress(_)
+                | StoreByteIndirect
+                | StoreByteOffset(_)
+                | StoreByteStackOffset(_)
+                | StoreIndirectPostInc
+                | StoreByteIndirectPostInc
+                | Call(_)
+                | CallOffset(_)
+                | CallRelative(_)
+                | Push
+                | PushPC
+                | CallIf(..)
+                | PushFlags
+                | PushAll
+                | Enter(_)
+                | CallInterrupt
+                | Int(_)
+                | SetInterrupt(_)
+        )
+    }
+
+    /// Whether this instruction faults in user mode: it changes the interrupt vector, returns from an interrupt, stops
+    /// at a breakpoint, or sets or clears one of the [`flag::PRIVILEGED`] flags.
+    pub fn is_privileged(&self) -> bool {
+        use Instruction::*;
+        match *self {
+            SetInterrupt(_) | ReturnInterrupt | Brk => true,
+            Set(flag) | Clear(flag) => flag::PRIVILEGED & (1 << flag) != 0,
+            _ => false,
+        }
+    }
+}
+
+impl<M: Memory, P: PortDevice> Emulator<M, P> {
     pub fn execute(&mut self, instruction: Instruction) {
+        if self.flags.get(flag::USER) && instruction.is_privileged() {
+            self.privilege_fault();
+            return;
+        }
         match instruction {
             Instruction::LoadFrom(reg) => self.a = self.register(reg),
             Instruction::StoreTo(reg) => *self.mut_register(reg) = self.a,
@@ -399,6 +1043,22 @@ impl<M: Memory> Emulator<M> {
             Instruction::StoreByteStackOffset(offset) => self
                 .memory
                 .write_byte(self.sp.wrapping_add(offset) as usize, self.a as u8),
+            Instruction::LoadIndirectPostInc => {
+                self.a = self.memory.read_word(self.b as usize);
+                self.b = self.b.wrapping_add(2);
+            }
+            Instruction::LoadByteIndirectPostInc => {
+                self.a = self.memory.read_byte(self.b as usize) as u16;
+                self.b = self.b.wrapping_add(1);
+            }
+            Instruction::StoreIndirectPostInc => {
+                self.memory.write_word(self.b as usize, self.a);
+                self.b = self.b.wrapping_add(2);
+            }
+            Instruction::StoreByteIndirectPostInc => {
+                self.memory.write_byte(self.b as usize, self.a as u8);
+                self.b = self.b.wrapping_add(1);
+            }
             Instruction::Not(reg) => {
                 *self.mut_register(reg) = !self.register(reg);
                 self.set_operation_flags(self.register(reg));
@@ -408,14 +1068,28 @@ impl<M: Memory> Emulator<M> {
                 let overflow = (self.register(reg) as i16).overflowing_add(1).1;
                 *self.mut_register(reg) = result;
                 self.set_operation_flags(self.register(reg));
-                self.flags |= (overflow as u16) << flag::OVERFLOW | (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
             }
             Instruction::Decrement(reg) => {
                 let (result, carry) = self.register(reg).overflowing_sub(1);
                 let overflow = (self.register(reg) as i16).overflowing_sub(1).1;
                 *self.mut_register(reg) = result;
                 self.set_operation_flags(self.register(reg));
-                self.flags |= (overflow as u16) << flag::OVERFLOW | (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
+            }
+            Instruction::SignExtendByte(reg) => {
+                *self.mut_register(reg) = self.register(reg) as i8 as u16;
+                self.set_operation_flags(self.register(reg));
+            }
+            Instruction::ZeroExtendByte(reg) => {
+                *self.mut_register(reg) = self.register(reg) & 0xFF;
+                self.set_operation_flags(self.register(reg));
+            }
+            Instruction::SwapBytes(reg) => {
+                *self.mut_register(reg) = self.register(reg).swap_bytes();
+                self.set_operation_flags(self.register(reg));
             }
             Instruction::And(reg) => {
                 self.a &= self.register(reg);
@@ -433,67 +1107,121 @@ impl<M: Memory> Emulator<M> {
                 let (result, carry) = self.a.overflowing_shl(self.register(reg) as u32);
                 self.a = result;
                 self.set_operation_flags(self.a);
-                self.flags |= (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::CARRY, carry);
             }
             Instruction::RightShift(reg) => {
                 let (result, carry) = self.a.overflowing_shr(self.register(reg) as u32);
                 self.a = result;
                 self.set_operation_flags(self.a);
-                self.flags |= (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::CARRY, carry);
+            }
+            Instruction::LeftShiftImmediate(amount) => {
+                let (result, carry) = self.a.overflowing_shl(amount as u32);
+                self.a = result;
+                self.set_operation_flags(self.a);
+                self.flags.assign(flag::CARRY, carry);
+            }
+            Instruction::RightShiftImmediate(amount) => {
+                let (result, carry) = self.a.overflowing_shr(amount as u32);
+                self.a = result;
+                self.set_operation_flags(self.a);
+                self.flags.assign(flag::CARRY, carry);
+            }
+            Instruction::RotateLeftImmediate(amount) => {
+                self.a = self.a.rotate_left(amount as u32);
+                self.set_operation_flags(self.a);
+                // The carry holds the last bit rotated out, which is now the lowest bit.
+                let carry = amount % 16 != 0 && self.a & 1 != 0;
+                self.flags.assign(flag::CARRY, carry);
+            }
+            Instruction::RotateRightImmediate(amount) => {
+                self.a = self.a.rotate_right(amount as u32);
+                self.set_operation_flags(self.a);
+                // The carry holds the last bit rotated out, which is now the highest bit.
+                let carry = amount % 16 != 0 && self.a & 0x8000 != 0;
+                self.flags.assign(flag::CARRY, carry);
             }
             Instruction::Add(reg) => {
                 let (result, carry) = self.a.overflowing_add(self.register(reg));
                 let overflow = (self.a as i16).overflowing_add(self.register(reg) as i16).1;
                 self.a = result;
                 self.set_operation_flags(self.a);
-                self.flags |= (overflow as u16) << flag::OVERFLOW | (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
             }
             Instruction::Subtract(reg) => {
                 let (result, carry) = self.a.overflowing_sub(self.register(reg));
                 let overflow = (self.a as i16).overflowing_sub(self.register(reg) as i16).1;
                 self.a = result;
                 self.set_operation_flags(self.a);
-                self.flags |= (overflow as u16) << flag::OVERFLOW | (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
             }
             Instruction::AddWithCarry(reg) => {
                 let (result, carry) = self
                     .a
-                    .carrying_add(self.register(reg), self.flags & (1 << flag::CARRY) != 0);
+                    .carrying_add(self.register(reg), self.flags.get(flag::CARRY));
                 let overflow = (self.a as i16)
-                    .carrying_add(
-                        self.register(reg) as i16,
-                        self.flags & (1 << flag::CARRY) != 0,
-                    )
+                    .carrying_add(self.register(reg) as i16, self.flags.get(flag::CARRY))
                     .1;
                 self.a = result;
                 self.set_operation_flags(self.a);
-                self.flags |= (overflow as u16) << flag::OVERFLOW | (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
             }
             Instruction::SubtractWithBorrow(reg) => {
                 let (result, carry) = self
                     .a
-                    .borrowing_sub(self.register(reg), self.flags & (1 << flag::CARRY) != 0);
+                    .borrowing_sub(self.register(reg), self.flags.get(flag::CARRY));
                 let overflow = (self.a as i16)
-                    .borrowing_sub(
-                        self.register(reg) as i16,
-                        self.flags & (1 << flag::CARRY) != 0,
-                    )
+                    .borrowing_sub(self.register(reg) as i16, self.flags.get(flag::CARRY))
                     .1;
                 self.a = result;
                 self.set_operation_flags(self.a);
-                self.flags |= (overflow as u16) << flag::OVERFLOW | (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
+            }
+            Instruction::AddImmediate(value) => {
+                let (result, carry) = self.a.overflowing_add(value);
+                let overflow = (self.a as i16).overflowing_add(value as i16).1;
+                self.a = result;
+                self.set_operation_flags(self.a);
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
+            }
+            Instruction::SubtractImmediate(value) => {
+                let (result, carry) = self.a.overflowing_sub(value);
+                let overflow = (self.a as i16).overflowing_sub(value as i16).1;
+                self.a = result;
+                self.set_operation_flags(self.a);
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
+            }
+            Instruction::AndImmediate(value) => {
+                self.a &= value;
+                self.set_operation_flags(self.a);
+            }
+            Instruction::OrImmediate(value) => {
+                self.a |= value;
+                self.set_operation_flags(self.a);
+            }
+            Instruction::XorImmediate(value) => {
+                self.a ^= value;
+                self.set_operation_flags(self.a);
             }
             Instruction::CompareA(reg) => {
                 let (result, carry) = self.a.overflowing_sub(self.register(reg));
                 let overflow = (self.a as i16).overflowing_sub(self.register(reg) as i16).1;
                 self.set_operation_flags(result);
-                self.flags |= (overflow as u16) << flag::OVERFLOW | (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
             }
             Instruction::CompareImmediate(reg, value) => {
                 let (result, carry) = self.register(reg).overflowing_sub(value);
                 let overflow = (self.register(reg) as i16).overflowing_sub(value as i16).1;
                 self.set_operation_flags(result);
-                self.flags |= (overflow as u16) << flag::OVERFLOW | (carry as u16) << flag::CARRY;
+                self.flags.assign(flag::OVERFLOW, overflow);
+                self.flags.assign(flag::CARRY, carry);
             }
             Instruction::Jump(address) => self.pc = address,
             Instruction::JumpOffset(offset) => self.pc = self.b.wrapping_add(offset),
@@ -531,6 +1259,12 @@ impl<M: Memory> Emulator<M> {
                     self.pc = self.pc.wrapping_add(offset)
                 }
             }
+            Instruction::LoopNotZero(offset) => {
+                self.c = self.c.wrapping_sub(1);
+                if self.c != 0 {
+                    self.pc = self.pc.wrapping_add_signed(offset as i16)
+                }
+            }
             Instruction::Call(address) => {
                 self.sp = self.sp.wrapping_sub(2);
                 self.memory.write_word(self.sp as usize, self.pc);
@@ -556,7 +1290,7 @@ impl<M: Memory> Emulator<M> {
             }
             Instruction::PushFlags => {
                 self.sp = self.sp.wrapping_sub(2);
-                self.memory.write_word(self.sp as usize, self.flags);
+                self.memory.write_word(self.sp as usize, self.flags.0);
             }
             Instruction::Pop => {
                 self.a = self.memory.read_word(self.sp as usize);
@@ -566,25 +1300,314 @@ impl<M: Memory> Emulator<M> {
                 self.pc = self.memory.read_word(self.sp as usize);
                 self.sp = self.sp.wrapping_add(2)
             }
+            Instruction::ReturnDeallocate(size) => {
+                self.pc = self.memory.read_word(self.sp as usize);
+                self.sp = self.sp.wrapping_add(2).wrapping_add(size as u16)
+            }
+            Instruction::CallIf(cond, address) => {
+                if self.check_condition(cond) {
+                    self.sp = self.sp.wrapping_sub(2);
+                    self.memory.write_word(self.sp as usize, self.pc);
+                    self.pc = address
+                }
+            }
+            Instruction::ReturnIf(cond) => {
+                if self.check_condition(cond) {
+                    self.pc = self.memory.read_word(self.sp as usize);
+                    self.sp = self.sp.wrapping_add(2)
+                }
+            }
             Instruction::PopFlags => {
-                self.flags = self.memory.read_word(self.sp as usize);
+                let popped = self.memory.read_word(self.sp as usize);
+                self.flags = if self.flags.get(flag::USER) {
+                    Flags((popped & !flag::PRIVILEGED) | (self.flags.0 & flag::PRIVILEGED))
+                } else {
+                    Flags(popped)
+                };
                 self.sp = self.sp.wrapping_add(2)
             }
-            Instruction::Input => {
-                let mut buf = [0; 1];
-                match stdin().lock().read_exact(&mut buf) {
-                    Ok(_) => self.a = buf[0] as u16,
-                    Err(_) => self.a = u16::MAX,
-                }
+            Instruction::PushAll => self.push_all(),
+            Instruction::PopAll => self.pop_all(),
+            Instruction::Enter(size) => {
+                self.sp = self.sp.wrapping_sub(2);
+                self.memory.write_word(self.sp as usize, self.b);
+                self.b = self.sp;
+                self.sp = self.sp.wrapping_sub(size);
+            }
+            Instruction::Leave => {
+                self.sp = self.b;
+                self.b = self.memory.read_word(self.sp as usize);
+                self.sp = self.sp.wrapping_add(2);
             }
-            Instruction::Output => {
-                print!("{}", self.a as u8 as char)
+            Instruction::Input => self.a = self.ports.read(self.d as u8),
+            Instruction::Output => self.ports.write(self.d as u8, self.a),
+            Instruction::Nop => {}
+            Instruction::Brk => {
+                self.flags.set(flag::BREAK);
+                self.flags.set(flag::HALT);
             }
             Instruction::SetInterrupt(address) => self.memory.write_word(0xFFFE, address),
             Instruction::CallInterrupt => self.interrupt(self.d),
             Instruction::ReturnInterrupt => self.handle_interrupt_return(),
-            Instruction::Clear(flag) => self.flags &= !(1 << flag),
-            Instruction::Set(flag) => self.flags |= 1 << flag,
+            Instruction::Int(number) => self.software_interrupt(number),
+            Instruction::Clear(flag) => self.flags.clear(flag),
+            Instruction::Set(flag) => self.flags.set(flag),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The valid opcodes, as listed in the table on [`Instruction`].
+    const OPCODES: &[(u8, u8)] = &[
+        (0x00, 0x5F),
+        (0x60, 0x62),
+        (0x64, 0x67),
+        (0x68, 0x6A),
+        (0x70, 0x9F),
+        (0xA0, 0xA4),
+        (0xA8, 0xAD),
+        (0xB0, 0xB1),
+        (0xB4, 0xB9),
+        (0xBC, 0xC8),
+        (0xD0, 0xD4),
+        (0xD8, 0xFF),
+    ];
+
+    #[test]
+    fn every_opcode_decodes_as_documented_and_encodes_back() {
+        for opcode in 0..=0xFF {
+            // The operand bytes double as the condition of `CallIf` and `ReturnIf`, so the first must be one.
+            let bytes = [opcode, 0x03, 0x34, 0x12];
+            let documented = OPCODES
+                .iter()
+                .any(|&(first, last)| (first..=last).contains(&opcode));
+            match Instruction::try_from_iter(&bytes) {
+                Ok((instruction, length)) => {
+                    assert!(documented, "${opcode:02X} decodes to {instruction:?}");
+                    let encoded = Vec::from(instruction);
+                    assert_eq!(encoded, bytes[..length as usize], "{instruction:?}");
+                    let text = instruction.to_string();
+                    assert_eq!(text.parse(), Ok(instruction), "{text}");
+                }
+                Err(err) => {
+                    assert!(!documented, "${opcode:02X} does not decode");
+                    assert_eq!(err, InstructionError::InvalidOpcode(opcode));
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "interrupt 8 does not fit in the opcode")]
+    fn encoding_rejects_an_interrupt_past_7() {
+        let _ = Vec::from(Instruction::Int(8));
+    }
+
+    #[test]
+    #[should_panic(expected = "condition 16 does not fit in the opcode")]
+    fn encoding_rejects_a_condition_past_15() {
+        let _ = Vec::from(Instruction::JumpOffsetIf(16, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "condition 16 is not a valid condition")]
+    fn encoding_rejects_a_call_condition_past_15() {
+        let _ = Vec::from(Instruction::CallIf(16, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "condition 255 is not a valid condition")]
+    fn encoding_rejects_a_return_condition_past_15() {
+        let _ = Vec::from(Instruction::ReturnIf(255));
+    }
+
+    /// An emulator with empty memory.
+    fn emulator() -> Emulator {
+        Emulator::new([0; crate::emulator::MEM_SIZE])
+    }
+
+    #[test]
+    fn shifts_and_rotates_take_an_immediate_amount() {
+        use Instruction::*;
+        let mut emu = emulator();
+        emu.a = 0x1234;
+        emu.execute(LeftShiftImmediate(4));
+        assert_eq!(emu.a, 0x2340);
+        emu.execute(RightShiftImmediate(8));
+        assert_eq!(emu.a, 0x0023);
+        emu.a = 0x8001;
+        emu.execute(RotateLeftImmediate(1));
+        assert_eq!((emu.a, emu.flags.get(flag::CARRY)), (0x0003, true));
+        emu.execute(RotateRightImmediate(2));
+        assert_eq!(
+            (emu.a, emu.flags.get(flag::CARRY), emu.flags.get(flag::SIGN)),
+            (0xC000, true, true)
+        );
+        // A full turn leaves the value alone and rotates nothing out.
+        emu.execute(RotateLeftImmediate(16));
+        assert_eq!((emu.a, emu.flags.get(flag::CARRY)), (0xC000, false));
+    }
+
+    #[test]
+    fn arithmetic_and_logic_take_an_immediate_operand() {
+        use Instruction::*;
+        let mut emu = emulator();
+        emu.a = 0x7FFF;
+        emu.execute(AddImmediate(1));
+        assert_eq!(emu.a, 0x8000);
+        assert!(
+            emu.flags.get(flag::OVERFLOW)
+                && emu.flags.get(flag::SIGN)
+                && !emu.flags.get(flag::CARRY)
+        );
+        emu.a = 0;
+        emu.execute(SubtractImmediate(1));
+        assert_eq!(emu.a, 0xFFFF);
+        assert!(emu.flags.get(flag::CARRY) && !emu.flags.get(flag::OVERFLOW));
+        emu.execute(AndImmediate(0x0FF0));
+        assert_eq!(emu.a, 0x0FF0);
+        emu.execute(OrImmediate(0x1001));
+        assert_eq!(emu.a, 0x1FF1);
+        emu.execute(XorImmediate(0x1FF1));
+        assert_eq!(emu.a, 0);
+        assert!(emu.flags.get(flag::ZERO));
+    }
+
+    #[test]
+    fn bytes_extend_to_words() {
+        use GeneralPurposeRegister::*;
+        use Instruction::*;
+        let mut emu = emulator();
+        emu.c = 0x1280;
+        emu.execute(SignExtendByte(C));
+        assert_eq!(emu.c, 0xFF80);
+        assert!(emu.flags.get(flag::SIGN));
+        emu.execute(ZeroExtendByte(C));
+        assert_eq!(emu.c, 0x0080);
+        assert!(!emu.flags.get(flag::SIGN));
+        emu.c = 0xAB7F;
+        emu.execute(SignExtendByte(C));
+        assert_eq!(emu.c, 0x007F);
+        emu.c = 0x1200;
+        emu.execute(ZeroExtendByte(C));
+        assert_eq!(emu.c, 0);
+        assert!(emu.flags.get(flag::ZERO));
+    }
+
+    #[test]
+    fn swap_exchanges_the_bytes_of_a_register() {
+        use GeneralPurposeRegister::*;
+        use Instruction::*;
+        let mut emu = emulator();
+        emu.d = 0x0080;
+        emu.execute(SwapBytes(D));
+        assert_eq!(emu.d, 0x8000);
+        assert!(emu.flags.get(flag::SIGN) && !emu.flags.get(flag::ZERO));
+        emu.execute(SwapBytes(D));
+        assert_eq!(emu.d, 0x0080);
+        assert!(!emu.flags.get(flag::SIGN));
+        emu.d = 0;
+        emu.execute(SwapBytes(D));
+        assert!(emu.flags.get(flag::ZERO));
+    }
+
+    #[test]
+    fn nop_does_nothing_and_brk_stops_with_a_break() {
+        let mut memory = [0; crate::emulator::MEM_SIZE];
+        memory[..3].copy_from_slice(&[0xD3, 0xD3, 0xD4]);
+        let mut emu = Emulator::new(memory);
+        let before = (emu.a, emu.b, emu.c, emu.d, emu.sp, emu.flags.0);
+        emu.advance();
+        assert_eq!(emu.pc, 1);
+        assert_eq!((emu.a, emu.b, emu.c, emu.d, emu.sp, emu.flags.0), before);
+        let result = emu.run(Some(10));
+        assert_eq!(result.reason, crate::emulator::StopReason::Break);
+        assert_eq!(result.steps, 2);
+        assert!(emu.flags.get(flag::BREAK) && emu.flags.get(flag::HALT));
+    }
+
+    #[test]
+    fn enter_and_leave_keep_the_frame_in_b() {
+        use Instruction::*;
+        let mut emu = emulator();
+        emu.sp = 0x8000;
+        emu.b = 0x1234;
+        emu.execute(Enter(6));
+        assert_eq!((emu.b, emu.sp), (0x7FFE, 0x7FF8));
+        assert_eq!(emu.memory.read_word(0x7FFE), 0x1234);
+        // Locals are addressed below the frame pointer, and nested frames chain through it.
+        emu.a = 0xBEEF;
+        emu.execute(StoreOffset(0xFFFE));
+        assert_eq!(emu.memory.read_word(0x7FFC), 0xBEEF);
+        emu.execute(Enter(0));
+        assert_eq!((emu.b, emu.sp), (0x7FF6, 0x7FF6));
+        emu.execute(Leave);
+        assert_eq!((emu.b, emu.sp), (0x7FFE, 0x7FF8));
+        emu.execute(Leave);
+        assert_eq!((emu.b, emu.sp), (0x1234, 0x8000));
+    }
+
+    #[test]
+    fn conditional_calls_and_returns_only_act_when_taken() {
+        use Instruction::*;
+        let mut emu = emulator();
+        emu.sp = 0x8000;
+        emu.pc = 0x0104;
+        emu.flags.clear(flag::ZERO);
+        emu.execute(CallIf(condition::ZERO, 0x2000));
+        assert_eq!((emu.pc, emu.sp), (0x0104, 0x8000));
+        emu.execute(CallIf(condition::NOT_ZERO, 0x2000));
+        assert_eq!((emu.pc, emu.sp), (0x2000, 0x7FFE));
+        assert_eq!(emu.memory.read_word(0x7FFE), 0x0104);
+        emu.execute(ReturnIf(condition::ZERO));
+        assert_eq!((emu.pc, emu.sp), (0x2000, 0x7FFE));
+        emu.execute(ReturnIf(condition::NOT_ZERO));
+        assert_eq!((emu.pc, emu.sp), (0x0104, 0x8000));
+    }
+
+    #[test]
+    fn post_increment_steps_b_by_the_access_size() {
+        use Instruction::*;
+        let mut emu = emulator();
+        emu.b = 0x4000;
+        emu.a = 0x1234;
+        emu.execute(StoreIndirectPostInc);
+        emu.a = 0xAB56;
+        emu.execute(StoreByteIndirectPostInc);
+        assert_eq!(emu.b, 0x4003);
+        assert_eq!(emu.memory.read_word(0x4000), 0x1234);
+        assert_eq!(emu.memory.read_byte(0x4002), 0x56);
+        emu.b = 0x4000;
+        emu.execute(LoadIndirectPostInc);
+        assert_eq!((emu.a, emu.b), (0x1234, 0x4002));
+        emu.execute(LoadByteIndirectPostInc);
+        assert_eq!((emu.a, emu.b), (0x0056, 0x4003));
+        // The pointer wraps at the top of memory.
+        emu.b = 0xFFFF;
+        emu.execute(LoadByteIndirectPostInc);
+        assert_eq!(emu.b, 0x0000);
+    }
+
+    #[test]
+    fn popping_the_flags_in_user_mode_keeps_the_privileged_ones() {
+        use Instruction::*;
+        let mut emu = emulator();
+        emu.sp = 0x8000;
+        emu.flags.set(flag::USER);
+        emu.flags.set_level(3);
+        emu.memory.write_word(0x8000, 0xFFFF);
+        emu.execute(PopFlags);
+        assert_eq!(emu.sp, 0x8002);
+        assert!(emu.flags.get(flag::ZERO) && emu.flags.get(flag::BREAK));
+        assert!(emu.flags.get(flag::USER) && emu.flags.level() == 3);
+        assert!(!emu.flags.get(flag::INTERRUPT) && !emu.flags.get(flag::HALT));
+        // Supervisor code restores them all.
+        emu.flags.clear(flag::USER);
+        emu.sp = 0x8000;
+        emu.execute(PopFlags);
+        assert_eq!(emu.flags.0, 0xFFFF);
+    }
+}
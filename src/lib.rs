@@ -1,8 +1,13 @@
-#![feature(bigint_helper_methods)]
+#![feature(signed_bigint_helpers)]
 
+pub mod assemble;
 pub mod condition;
+pub mod debug_info;
+pub mod disassemble;
+pub mod elf;
 pub mod emulator;
 pub mod flag;
 pub mod isa;
 pub mod memory;
+pub mod port;
 pub mod register;
@@ -6,23 +6,264 @@
 //! - D is used for port indexing. It is not used for memory access.
 //!
 //! The GPRs may be used for any arithmetic operation.
+//!
+//! Usage: `asm [image] [--trace ADDRESS=MESSAGE]... [--symbols FILE] [--debug-info FILE [--source FILE]] [--json]`. The
+//! image is loaded at address 0; without one, a built-in demo program is run.
+//!
+//! A tracepoint prints its message to stderr each time the instruction at `ADDRESS` is about to run, without stopping.
+//! `ADDRESS` is hexadecimal, or a symbol if `--symbols` reads a symbol table written by `compile --symbols`. `{a}`,
+//! `{b}`, `{c}`, `{d}`, `{pc}`, `{sp}` and `{flags}` in the message are replaced by the register's value, and `{[X]}` by
+//! the word in memory at `X`: a hexadecimal address, a symbol, or a register holding the address, as in `{[b]}`.
+//!
+//! `--debug-info` reads a line table written by `compile --debug-info` and prints the source line of each instruction
+//! to stderr as it runs: its text if `--source` gives the source file, otherwise its line and column.
+//!
+//! `--json` prints the result of the run to stderr as a JSON object once the program stops: why it stopped, the cycle
+//! count, the final program counter, and the faulting address and last interrupt if there were any.
 
 use asm::condition;
-use asm::emulator::{Emulator, MEM_SIZE};
+use asm::debug_info::LineTable;
+use asm::emulator::{Emulator, MEM_SIZE, StopReason};
 use asm::flag;
 use asm::isa::Instruction;
 use asm::memory::Memory;
 use asm::register::GeneralPurposeRegister;
+use std::collections::HashMap;
+use std::process::exit;
 
 fn main() {
-    use GeneralPurposeRegister::*;
-    use Instruction::*;
-
     let print_status: bool = false;
 
     let mut emu = Emulator::<[u8; MEM_SIZE]>::new([0; MEM_SIZE]);
 
-    emu.memory.write_array(
+    let mut image = None;
+    let mut tracepoint_args = Vec::new();
+    let mut symbols = HashMap::new();
+    let mut lines = None;
+    let mut source = None;
+    let mut json = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--trace" {
+            tracepoint_args.push(args.next().unwrap_or_else(|| usage()));
+        } else if arg == "--symbols" {
+            let path = args.next().unwrap_or_else(|| usage());
+            let table = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+                eprintln!("{path}: {err}");
+                exit(1);
+            });
+            for (number, line) in table.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let Some((address, name)) = parse_symbol(line) else {
+                    eprintln!("{path}:{}: expected `ADDRESS NAME`", number + 1);
+                    exit(1);
+                };
+                symbols.insert(name, address);
+            }
+        } else if arg == "--debug-info" {
+            let path = args.next().unwrap_or_else(|| usage());
+            let table = std::fs::read(&path).map(|bytes| LineTable::from_bytes(&bytes));
+            match table {
+                Ok(Some(table)) => lines = Some(table),
+                Ok(None) => {
+                    eprintln!("{path}: not a line table");
+                    exit(1);
+                }
+                Err(err) => {
+                    eprintln!("{path}: {err}");
+                    exit(1);
+                }
+            }
+        } else if arg == "--source" {
+            let path = args.next().unwrap_or_else(|| usage());
+            match std::fs::read_to_string(&path) {
+                Ok(text) => source = Some(text),
+                Err(err) => {
+                    eprintln!("{path}: {err}");
+                    exit(1);
+                }
+            }
+        } else if arg == "--json" {
+            json = true;
+        } else if image.is_none() {
+            image = Some(arg);
+        } else {
+            usage();
+        }
+    }
+    // Tracepoints are parsed once every option is read, so that they can name symbols from a later `--symbols`.
+    let mut tracepoints = HashMap::new();
+    for arg in &tracepoint_args {
+        let Some((address, message)) = parse_tracepoint(arg, &symbols) else {
+            eprintln!("invalid tracepoint `{arg}`");
+            usage();
+        };
+        tracepoints.insert(address, message);
+    }
+    let source_lines: Vec<&str> = source
+        .as_deref()
+        .map_or(Vec::new(), |text| text.lines().collect());
+
+    match image {
+        Some(path) => match std::fs::read(&path) {
+            Ok(image) if image.len() <= MEM_SIZE => emu.memory.write_array(0x0000, &image),
+            Ok(_) => {
+                eprintln!("{path}: image is larger than memory");
+                exit(1);
+            }
+            Err(err) => {
+                eprintln!("{path}: {err}");
+                exit(1);
+            }
+        },
+        None => load_hello_world(&mut emu.memory),
+    }
+
+    let result = emu.run_traced(None, |emu| {
+        if print_status {
+            eprintln!(
+                "A: {:04X} | B: {:04X} | C: {:04X} | D: {:04X}  |  SP: {:04X}  |  FLAGS: {:016b}  |  PC: {:04X}  |  {}",
+                emu.a,
+                emu.b,
+                emu.c,
+                emu.d,
+                emu.sp,
+                emu.flags.0,
+                emu.pc,
+                match emu.next_instruction() {
+                    Ok((instruction, _)) => instruction.to_string(),
+                    Err(err) => format!("{err:?}"),
+                }
+            );
+        }
+        if let Some(entry) = lines.as_ref().and_then(|lines| emu.source_line(lines)) {
+            let text = entry.line.checked_sub(1).and_then(|line| source_lines.get(line as usize));
+            match text {
+                Some(text) => eprintln!("{:04X}  {:>5}  {}", emu.pc, entry.line, text.trim()),
+                None => eprintln!("{:04X}  line {}:{}", emu.pc, entry.line, entry.column),
+            }
+        }
+        if let Some(message) = tracepoints.get(&emu.pc) {
+            eprintln!("{}", format_trace(message, emu));
+        }
+    });
+    if json {
+        eprintln!("{}", result.to_json());
+    }
+    if let StopReason::InvalidInstruction(err) = result.reason {
+        eprintln!("{:04X}: {err:?}", emu.pc);
+        exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: asm [image] [--trace ADDRESS=MESSAGE]... [--symbols FILE] [--debug-info FILE [--source FILE]] [--json]"
+    );
+    exit(2);
+}
+
+/// Parse a line of a symbol table, `ADDRESS NAME` with the address in hexadecimal.
+fn parse_symbol(line: &str) -> Option<(u16, String)> {
+    let (address, name) = line.trim().split_once(' ')?;
+    Some((
+        u16::from_str_radix(address, 16).ok()?,
+        name.trim().to_string(),
+    ))
+}
+
+/// Parse a hexadecimal address with an optional `0x` or `$` prefix.
+fn parse_address(address: &str) -> Option<u16> {
+    let address = address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix('$'))
+        .unwrap_or(address);
+    u16::from_str_radix(address, 16).ok()
+}
+
+/// Parse `ADDRESS=MESSAGE`, with the address a symbol from `symbols` or in hexadecimal. Symbols in the memory operands
+/// of the message are replaced by their addresses, and any other operand must be an address or a register.
+fn parse_tracepoint(arg: &str, symbols: &HashMap<String, u16>) -> Option<(u16, String)> {
+    let (address, message) = arg.split_once('=')?;
+    let address = symbols
+        .get(address)
+        .copied()
+        .or_else(|| parse_address(address))?;
+    let mut resolved = String::new();
+    let mut rest = message;
+    while let Some(start) = rest.find("{[") {
+        let end = start + rest[start..].find("]}")?;
+        let operand = &rest[start + 2..end];
+        resolved.push_str(&rest[..start]);
+        match symbols.get(operand) {
+            Some(value) => resolved.push_str(&format!("{{[${value:04X}]}}")),
+            None if register_name(operand) || parse_address(operand).is_some() => {
+                resolved.push_str(&rest[start..end + 2])
+            }
+            None => return None,
+        }
+        rest = &rest[end + 2..];
+    }
+    resolved.push_str(rest);
+    Some((address, resolved))
+}
+
+/// Whether `name` is a register a tracepoint message can show.
+fn register_name(name: &str) -> bool {
+    matches!(name, "a" | "b" | "c" | "d" | "pc" | "sp" | "flags")
+}
+
+/// Value of the register called `name` in a tracepoint message.
+fn register_value<M: Memory>(name: &str, emu: &Emulator<M>) -> Option<u16> {
+    Some(match name {
+        "a" => emu.a,
+        "b" => emu.b,
+        "c" => emu.c,
+        "d" => emu.d,
+        "pc" => emu.pc,
+        "sp" => emu.sp,
+        "flags" => emu.flags.0,
+        _ => return None,
+    })
+}
+
+/// Replace register and memory placeholders in a tracepoint message. Other text in braces is left alone.
+fn format_trace<M: Memory>(message: &str, emu: &Emulator<M>) -> String {
+    let mut output = String::new();
+    let mut rest = message;
+    while let Some((start, end)) = rest
+        .find('{')
+        .and_then(|start| Some((start, start + rest[start..].find('}')?)))
+    {
+        let name = &rest[start + 1..end];
+        let value = match name
+            .strip_prefix('[')
+            .and_then(|name| name.strip_suffix(']'))
+        {
+            Some(address) => register_value(address, emu)
+                .or_else(|| parse_address(address))
+                .map(|address| emu.memory.read_word(address as usize)),
+            None => register_value(name, emu),
+        };
+        output.push_str(&rest[..start]);
+        match value {
+            Some(value) => output.push_str(&format!("{value:04X}")),
+            None => output.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Load the built-in demo program, used when no image is given on the command line.
+fn load_hello_world(memory: &mut impl Memory) {
+    use GeneralPurposeRegister::*;
+    use Instruction::*;
+
+    memory.write_array(
         0x0000,
         &Instruction::make_bytes(&[
             /* $0000 */ Ok(LoadImmediate(B, 0x4000)),
@@ -31,38 +272,53 @@ fn main() {
         ]),
     );
 
-    emu.memory.write_array(
+    memory.write_array(
         0x2000,
         &Instruction::make_bytes(&[
-            /* $2000 */ Ok(LoadByteIndirect),
+            /* $2000 */ Ok(LoadByteIndirectPostInc),
             /* $2001 */ Ok(And(A)),
-            /* $2002 */ Ok(JumpRelativeIf(condition::ZERO, 5)),
+            /* $2002 */ Ok(JumpRelativeIf(condition::ZERO, 4)),
             /* $2005 */ Ok(Output),
-            /* $2006 */ Ok(Increment(B)),
-            /* $2007 */ Ok(JumpRelative(-10i16 as u16)),
-            /* $200A */ Ok(Return),
+            /* $2006 */ Ok(JumpRelative(-9i16 as u16)),
+            /* $2009 */ Ok(Return),
         ]),
     );
 
-    emu.memory.write_array(
+    memory.write_array(
         0x4000,
         &Instruction::make_bytes(&[/* $4000 */ Err("Hello, World!\n\0".as_bytes())]),
     );
+}
 
-    while emu.flags & (1 << flag::HALT) == 0 {
-        if print_status {
-            eprintln!(
-                "A: {:04X} | B: {:04X} | C: {:04X} | D: {:04X}  |  SP: {:04X}  |  FLAGS: {:016b}  |  PC: {:04X}  |  {:?}",
-                emu.a,
-                emu.b,
-                emu.c,
-                emu.d,
-                emu.sp,
-                emu.flags,
-                emu.pc,
-                emu.next_instruction()
-            );
-        }
-        emu.advance();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracepoints_resolve_symbols() {
+        let symbols = HashMap::from([("loop".to_string(), 0x0120), ("count".to_string(), 0x4000)]);
+        assert_eq!(
+            parse_tracepoint("loop=C={c} count={[count]}", &symbols),
+            Some((0x0120, "C={c} count={[$4000]}".to_string()))
+        );
+        assert_eq!(
+            parse_tracepoint("$1F=at {[b]} {[0x10]}", &symbols),
+            Some((0x001F, "at {[b]} {[0x10]}".to_string()))
+        );
+        assert_eq!(parse_tracepoint("missing=hit", &symbols), None);
+        assert_eq!(parse_tracepoint("10={[missing]}", &symbols), None);
+    }
+
+    #[test]
+    fn tracepoint_messages_show_registers_and_memory() {
+        let mut emu = Emulator::<[u8; MEM_SIZE]>::new([0; MEM_SIZE]);
+        emu.b = 0x4002;
+        emu.c = 7;
+        emu.memory.write_word(0x4000, 0xBEEF);
+        emu.memory.write_word(0x4002, 0x1234);
+        assert_eq!(
+            format_trace("C={c} [$4000]={[$4000]} [B]={[b]} {x} {", &emu),
+            "C=0007 [$4000]=BEEF [B]=1234 {x} {"
+        );
     }
 }
@@ -1,3 +1,13 @@
+use std::cell::RefCell;
+
+/// Byte-addressable memory.
+///
+/// Multi-byte accesses are composed of byte accesses in little-endian order. An access that runs past the end of
+/// memory wraps around to address 0. Memories made of several parts, like [`SegmentedMemory`], can instead follow a
+/// [`WordPolicy`] where the parts meet.
+///
+/// The trait is dyn-compatible, so different kinds of memory can be used through `Box<dyn Memory>`. Generic helpers
+/// live in [`MemoryExt`].
 pub trait Memory {
     fn len(&self) -> usize;
 
@@ -6,22 +16,128 @@ pub trait Memory {
     }
 
     fn read_byte(&self, address: usize) -> u8;
-    fn read_word(&self, address: usize) -> u16;
     fn write_byte(&mut self, address: usize, value: u8);
-    fn write_word(&mut self, address: usize, value: u16);
 
-    fn read_array<const N: usize>(&self, address: usize) -> [u8; N] {
-        let mut result = [0; N];
-        for (addr, item) in result.iter_mut().enumerate() {
-            *item = self.read_byte(address.wrapping_add(addr));
+    fn read_word(&self, address: usize) -> u16 {
+        u16::from_le_bytes([
+            self.read_byte(address),
+            self.read_byte(wrap(address + 1, self.len())),
+        ])
+    }
+    fn write_word(&mut self, address: usize, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write_byte(address, low);
+        self.write_byte(wrap(address + 1, self.len()), high);
+    }
+
+    fn read_slice(&self, address: usize, buffer: &mut [u8]) {
+        for (idx, item) in buffer.iter_mut().enumerate() {
+            *item = self.read_byte(wrap(address + idx, self.len()));
         }
-        result
     }
     fn write_array(&mut self, address: usize, bytes: &[u8]) {
         for (idx, item) in bytes.iter().enumerate() {
-            self.write_byte(address.wrapping_add(idx), *item);
+            self.write_byte(wrap(address + idx, self.len()), *item);
         }
     }
+
+    /// Writes ignored because they hit read-only memory, since the last call. Memory without read-only parts never
+    /// ignores a write.
+    fn take_read_only_writes(&mut self) -> Vec<ReadOnlyWrite> {
+        Vec::new()
+    }
+
+    /// Addresses of word accesses refused under [`WordPolicy::Fault`] since the last call.
+    fn take_word_faults(&mut self) -> Vec<usize> {
+        Vec::new()
+    }
+
+    /// Whether a write to `address` would be kept.
+    fn is_writable(&self, address: usize) -> bool {
+        address < self.len()
+    }
+
+    /// Whether anything is mapped at `address`. Fetching an instruction byte from an unmapped address is a bus fault.
+    /// Memories that repeat across the address space map every address.
+    fn is_mapped(&self, _address: usize) -> bool {
+        true
+    }
+}
+
+/// `address` wrapped around to the start of a memory of `len` bytes. Empty memory has no addresses to wrap to, so
+/// everything maps to 0.
+fn wrap(address: usize, len: usize) -> usize {
+    address.checked_rem(len).unwrap_or(0)
+}
+
+/// What a word access does when its two bytes fall in different segments of a [`SegmentedMemory`], or when it runs
+/// past the end of the address space.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum WordPolicy {
+    /// Each byte goes to whatever is mapped at its address, running past the end of the address space to address 0.
+    #[default]
+    Split,
+    /// The second byte wraps around to the start of the segment the first byte is in, as if each segment were an
+    /// address space of its own.
+    Wrap,
+    /// The access is not made: a read gives 0 and a write is ignored. The address is reported by
+    /// [`Memory::take_word_faults`].
+    Fault,
+}
+
+/// A write that read-only memory ignored.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ReadOnlyWrite {
+    pub address: usize,
+    pub value: u8,
+}
+
+/// Generic helpers available on every [`Memory`], including `dyn Memory`.
+pub trait MemoryExt: Memory {
+    fn read_array<const N: usize>(&self, address: usize) -> [u8; N] {
+        let mut result = [0; N];
+        self.read_slice(address, &mut result);
+        result
+    }
+}
+
+impl<M: Memory + ?Sized> MemoryExt for M {}
+
+impl<M: Memory + ?Sized> Memory for Box<M> {
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn read_byte(&self, address: usize) -> u8 {
+        (**self).read_byte(address)
+    }
+    fn write_byte(&mut self, address: usize, value: u8) {
+        (**self).write_byte(address, value)
+    }
+    fn read_word(&self, address: usize) -> u16 {
+        (**self).read_word(address)
+    }
+    fn write_word(&mut self, address: usize, value: u16) {
+        (**self).write_word(address, value)
+    }
+    fn read_slice(&self, address: usize, buffer: &mut [u8]) {
+        (**self).read_slice(address, buffer)
+    }
+    fn write_array(&mut self, address: usize, bytes: &[u8]) {
+        (**self).write_array(address, bytes)
+    }
+    fn take_read_only_writes(&mut self) -> Vec<ReadOnlyWrite> {
+        (**self).take_read_only_writes()
+    }
+    fn take_word_faults(&mut self) -> Vec<usize> {
+        (**self).take_word_faults()
+    }
+    fn is_writable(&self, address: usize) -> bool {
+        (**self).is_writable(address)
+    }
+    fn is_mapped(&self, address: usize) -> bool {
+        (**self).is_mapped(address)
+    }
 }
 
 impl Memory for [u8] {
@@ -32,16 +148,9 @@ impl Memory for [u8] {
     fn read_byte(&self, address: usize) -> u8 {
         self[address]
     }
-    fn read_word(&self, address: usize) -> u16 {
-        u16::from_le_bytes([self.read_byte(address), self.read_byte(address + 1)])
-    }
     fn write_byte(&mut self, address: usize, value: u8) {
         self[address] = value;
     }
-    fn write_word(&mut self, address: usize, value: u16) {
-        self.write_byte(address, value as u8);
-        self.write_byte(address + 1, (value >> 8) as u8);
-    }
 }
 
 impl<const N: usize> Memory for [u8; N] {
@@ -53,15 +162,240 @@ impl<const N: usize> Memory for [u8; N] {
         self[address]
     }
 
-    fn read_word(&self, address: usize) -> u16 {
-        u16::from_le_bytes([self.read_byte(address), self.read_byte(address + 1)])
+    fn write_byte(&mut self, address: usize, value: u8) {
+        self[address] = value;
+    }
+}
+
+/// A part of a [`SegmentedMemory`].
+pub struct Segment {
+    /// Address of the first byte of the segment.
+    pub start: usize,
+    pub memory: Box<dyn Memory>,
+    /// Writes to read-only segments are ignored, and reported by [`Memory::take_read_only_writes`].
+    pub read_only: bool,
+}
+
+/// An address space made of any number of memories mapped at fixed addresses, such as RAM, a device window and ROM.
+///
+/// Later segments take precedence where segments overlap. Unmapped addresses read as 0 and ignore writes. Word
+/// accesses follow [`SegmentedMemory::word_policy`] where two segments, or a segment and unmapped space, meet.
+pub struct SegmentedMemory {
+    len: usize,
+    segments: Vec<Segment>,
+    read_only_writes: Vec<ReadOnlyWrite>,
+    /// What word accesses across a boundary do.
+    pub word_policy: WordPolicy,
+    /// Word accesses refused under [`WordPolicy::Fault`]. Reads record them too, through a shared reference.
+    word_faults: RefCell<Vec<usize>>,
+}
+
+impl SegmentedMemory {
+    /// Create an address space of `len` bytes with nothing mapped, splitting word accesses across boundaries.
+    pub fn new(len: usize) -> Self {
+        Self::with_policy(len, WordPolicy::default())
+    }
+
+    /// Create an address space of `len` bytes with nothing mapped, following `word_policy` across boundaries.
+    pub fn with_policy(len: usize, word_policy: WordPolicy) -> Self {
+        Self {
+            len,
+            segments: Vec::new(),
+            read_only_writes: Vec::new(),
+            word_policy,
+            word_faults: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Map `memory` so that its address 0 appears at `start`.
+    pub fn map(&mut self, start: usize, memory: Box<dyn Memory>, read_only: bool) {
+        self.segments.push(Segment {
+            start,
+            memory,
+            read_only,
+        });
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    fn segment(&self, address: usize) -> Option<&Segment> {
+        self.segments
+            .iter()
+            .rev()
+            .find(|seg| (seg.start..seg.start + seg.memory.len()).contains(&address))
+    }
+
+    fn segment_mut(&mut self, address: usize) -> Option<&mut Segment> {
+        self.segments
+            .iter_mut()
+            .rev()
+            .find(|seg| (seg.start..seg.start + seg.memory.len()).contains(&address))
+    }
+
+    /// Index of the segment `address` falls in, or `None` if it is unmapped.
+    fn segment_index(&self, address: usize) -> Option<usize> {
+        self.segments
+            .iter()
+            .rposition(|seg| (seg.start..seg.start + seg.memory.len()).contains(&address))
+    }
+
+    /// Address of the second byte of a word at `address` under the word policy, or `None` if the access faults.
+    fn second_byte(&self, address: usize) -> Option<usize> {
+        let next = wrap(address + 1, self.len);
+        let first = self.segment_index(address);
+        if next == address + 1 && first == self.segment_index(next) {
+            return Some(next);
+        }
+        match (self.word_policy, first) {
+            (WordPolicy::Split, _) | (WordPolicy::Wrap, None) => Some(next),
+            (WordPolicy::Wrap, Some(index)) => {
+                let seg = &self.segments[index];
+                Some(seg.start + (address + 1 - seg.start) % seg.memory.len())
+            }
+            (WordPolicy::Fault, _) => {
+                self.word_faults.borrow_mut().push(address);
+                None
+            }
+        }
+    }
+}
+
+impl Memory for SegmentedMemory {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read_byte(&self, address: usize) -> u8 {
+        match self.segment(address) {
+            Some(seg) => seg.memory.read_byte(address - seg.start),
+            None => 0,
+        }
     }
 
     fn write_byte(&mut self, address: usize, value: u8) {
-        self[address] = value;
+        let Some(seg) = self.segment_mut(address) else {
+            return;
+        };
+        if seg.read_only {
+            self.read_only_writes.push(ReadOnlyWrite { address, value });
+        } else {
+            seg.memory.write_byte(address - seg.start, value);
+        }
+    }
+
+    fn read_word(&self, address: usize) -> u16 {
+        match self.second_byte(address) {
+            Some(next) => u16::from_le_bytes([self.read_byte(address), self.read_byte(next)]),
+            None => 0,
+        }
     }
 
     fn write_word(&mut self, address: usize, value: u16) {
-        self.write_byte(address, value as u8);
+        if let Some(next) = self.second_byte(address) {
+            let [low, high] = value.to_le_bytes();
+            self.write_byte(address, low);
+            self.write_byte(next, high);
+        }
+    }
+
+    fn take_read_only_writes(&mut self) -> Vec<ReadOnlyWrite> {
+        std::mem::take(&mut self.read_only_writes)
+    }
+
+    fn take_word_faults(&mut self) -> Vec<usize> {
+        self.word_faults.take()
+    }
+
+    fn is_writable(&self, address: usize) -> bool {
+        self.segment(address).is_some_and(|seg| !seg.read_only)
+    }
+
+    fn is_mapped(&self, address: usize) -> bool {
+        self.segment(address).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Eight bytes of address space made of two four-byte segments, holding 0 to 7.
+    fn two_segments(word_policy: WordPolicy) -> SegmentedMemory {
+        let mut memory = SegmentedMemory::with_policy(8, word_policy);
+        memory.map(0, Box::new([0, 1, 2, 3]), false);
+        memory.map(4, Box::new([4, 5, 6, 7]), false);
+        memory
+    }
+
+    #[test]
+    fn words_inside_a_segment_ignore_the_policy() {
+        for policy in [WordPolicy::Split, WordPolicy::Wrap, WordPolicy::Fault] {
+            let mut memory = two_segments(policy);
+            assert_eq!(memory.read_word(1), 0x0201);
+            memory.write_word(5, 0xBBAA);
+            assert_eq!(bytes(&memory, 4, 4), [4, 0xAA, 0xBB, 7]);
+            assert!(memory.take_word_faults().is_empty());
+        }
+    }
+
+    #[test]
+    fn split_words_span_both_segments() {
+        let mut memory = two_segments(WordPolicy::Split);
+        assert_eq!(memory.read_word(3), 0x0403);
+        memory.write_word(3, 0xBBAA);
+        assert_eq!(bytes(&memory, 0, 8), [0, 1, 2, 0xAA, 0xBB, 5, 6, 7]);
+    }
+
+    #[test]
+    fn split_words_run_past_the_end_to_address_0() {
+        let mut memory = two_segments(WordPolicy::Split);
+        assert_eq!(memory.read_word(7), 0x0007);
+        memory.write_word(7, 0xBBAA);
+        assert_eq!(bytes(&memory, 0, 8), [0xBB, 1, 2, 3, 4, 5, 6, 0xAA]);
+    }
+
+    #[test]
+    fn wrapped_words_stay_in_their_segment() {
+        let mut memory = two_segments(WordPolicy::Wrap);
+        assert_eq!(memory.read_word(3), 0x0003);
+        assert_eq!(memory.read_word(7), 0x0407);
+        memory.write_word(3, 0xBBAA);
+        assert_eq!(bytes(&memory, 0, 8), [0xBB, 1, 2, 0xAA, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn faulting_words_are_not_made() {
+        let mut memory = two_segments(WordPolicy::Fault);
+        assert_eq!(memory.read_word(3), 0);
+        memory.write_word(7, 0xBBAA);
+        assert_eq!(bytes(&memory, 0, 8), [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(memory.take_word_faults(), [3, 7]);
+        assert!(memory.take_word_faults().is_empty());
+    }
+
+    #[test]
+    fn words_into_unmapped_space_follow_the_policy() {
+        let mut memory = SegmentedMemory::with_policy(8, WordPolicy::Fault);
+        memory.map(0, Box::new([1, 2]), false);
+        assert_eq!(memory.read_word(1), 0);
+        assert_eq!(memory.read_word(4), 0);
+        assert_eq!(memory.take_word_faults(), [1]);
+    }
+
+    #[test]
+    fn empty_memory_does_not_divide_by_zero() {
+        let mut memory = SegmentedMemory::new(0);
+        assert_eq!(memory.read_word(0), 0);
+        memory.write_word(0, 0xFFFF);
+        memory.write_array(0, &[1, 2, 3]);
+    }
+
+    /// `len` bytes of `memory` from `address`.
+    fn bytes(memory: &impl Memory, address: usize, len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; len];
+        memory.read_slice(address, &mut buffer);
+        buffer
     }
 }
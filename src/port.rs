@@ -0,0 +1,348 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{Read, stdin};
+use std::rc::Rc;
+
+/// Number of I/O ports. `Input` and `Output` select a port with the lower byte of the data register.
+pub const PORT_COUNT: usize = 256;
+
+/// A device reached through the `Input` and `Output` instructions.
+pub trait PortDevice {
+    /// Read a value from `port` into the accumulator.
+    fn read(&mut self, port: u8) -> u16;
+    /// Write the accumulator to `port`.
+    fn write(&mut self, port: u8, value: u16);
+}
+
+impl<P: PortDevice + ?Sized> PortDevice for Box<P> {
+    fn read(&mut self, port: u8) -> u16 {
+        (**self).read(port)
+    }
+    fn write(&mut self, port: u8, value: u16) {
+        (**self).write(port, value)
+    }
+}
+
+/// Shares a device between the port space and its owner, so that it can still be inspected after being attached.
+impl<P: PortDevice + ?Sized> PortDevice for Rc<RefCell<P>> {
+    fn read(&mut self, port: u8) -> u16 {
+        self.borrow_mut().read(port)
+    }
+    fn write(&mut self, port: u8, value: u16) {
+        self.borrow_mut().write(port, value)
+    }
+}
+
+/// Standard input and output. Reads return the next byte of input, or `0xFFFF` at the end of input; writes print the
+/// lower byte as a character.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Console;
+
+impl PortDevice for Console {
+    fn read(&mut self, _port: u8) -> u16 {
+        let mut buf = [0; 1];
+        match stdin().lock().read_exact(&mut buf) {
+            Ok(_) => buf[0] as u16,
+            Err(_) => u16::MAX,
+        }
+    }
+
+    fn write(&mut self, _port: u8, value: u16) {
+        print!("{}", value as u8 as char)
+    }
+}
+
+/// The port space: one optional device per port. Ports without a device read as `0xFFFF` and ignore writes.
+pub struct Ports {
+    devices: Vec<Option<Box<dyn PortDevice>>>,
+}
+
+impl Ports {
+    /// Create a port space with no devices attached.
+    pub fn new() -> Self {
+        Self {
+            devices: (0..PORT_COUNT).map(|_| None).collect(),
+        }
+    }
+
+    /// Create a port space with a copy of `device` attached to every port.
+    pub fn with_device(device: impl PortDevice + Clone + 'static) -> Self {
+        Self {
+            devices: (0..PORT_COUNT)
+                .map(|_| Some(Box::new(device.clone()) as Box<dyn PortDevice>))
+                .collect(),
+        }
+    }
+
+    /// Attach `device` to `port`, returning the device previously attached there.
+    pub fn attach(&mut self, port: u8, device: Box<dyn PortDevice>) -> Option<Box<dyn PortDevice>> {
+        self.devices[port as usize].replace(device)
+    }
+
+    /// Detach and return the device attached to `port`.
+    pub fn detach(&mut self, port: u8) -> Option<Box<dyn PortDevice>> {
+        self.devices[port as usize].take()
+    }
+}
+
+impl Default for Ports {
+    /// A port space with the [`Console`] attached to every port, so that programs reach it whatever the data
+    /// register holds. Attach other devices over it as needed.
+    fn default() -> Self {
+        Self::with_device(Console)
+    }
+}
+
+impl std::fmt::Debug for Ports {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let attached: Vec<usize> = (0..PORT_COUNT)
+            .filter(|&port| self.devices[port].is_some())
+            .collect();
+        f.debug_struct("Ports")
+            .field("attached", &attached)
+            .finish()
+    }
+}
+
+impl PortDevice for Ports {
+    fn read(&mut self, port: u8) -> u16 {
+        match &mut self.devices[port as usize] {
+            Some(device) => device.read(port),
+            None => u16::MAX,
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u16) {
+        if let Some(device) = &mut self.devices[port as usize] {
+            device.write(port, value);
+        }
+    }
+}
+
+/// A stand-in device for testing guest code. Reads return a fixed sequence of values, repeating the last one once the
+/// sequence runs out, and writes are recorded.
+///
+/// For example, `ScriptedDevice::new([0x00, 0x80])` models a status port that reads busy once, then ready forever.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct ScriptedDevice {
+    reads: VecDeque<u16>,
+    /// Value read once the script is exhausted. `0xFFFF` if the script was empty.
+    last: u16,
+    /// Every value written to the device, with the port it was written to, in order.
+    pub writes: Vec<(u8, u16)>,
+}
+
+impl ScriptedDevice {
+    pub fn new(reads: impl IntoIterator<Item = u16>) -> Self {
+        Self {
+            reads: reads.into_iter().collect(),
+            last: u16::MAX,
+            writes: Vec::new(),
+        }
+    }
+
+    /// Append `value` to the values still to be read.
+    pub fn push_read(&mut self, value: u16) {
+        self.reads.push_back(value);
+    }
+}
+
+impl PortDevice for ScriptedDevice {
+    fn read(&mut self, _port: u8) -> u16 {
+        if let Some(value) = self.reads.pop_front() {
+            self.last = value;
+        }
+        self.last
+    }
+
+    fn write(&mut self, port: u8, value: u16) {
+        self.writes.push((port, value));
+    }
+}
+
+/// Ports a [`Mailbox`] end takes, counted from the base port it is attached at. The first
+/// [`MAILBOX_REGISTERS`] are the shared registers.
+pub const MAILBOX_PORTS: u8 = 6;
+/// Number of shared registers in a [`Mailbox`].
+pub const MAILBOX_REGISTERS: u8 = 4;
+/// Offset of the doorbell port from the base port of a [`Mailbox`] end.
+pub const MAILBOX_DOORBELL: u8 = 4;
+/// Offset of the semaphore port from the base port of a [`Mailbox`] end.
+pub const MAILBOX_SEMAPHORE: u8 = 5;
+
+/// A mailbox between two processors, such as two cores or a core and a host-side co-processor. Each side reaches it
+/// through a [`MailboxEnd`] spanning [`MAILBOX_PORTS`] ports from a base port:
+///
+/// - The shared registers can be read and written by either side.
+/// - Writing the doorbell rings the other side with the value written. Reading it returns the value the other side
+///   rang with and clears it, or `0xFFFF` if it has not rung since.
+/// - Reading the semaphore tries to take it: the read returns 0 if this side now holds it and 1 if the other side
+///   does. Writing any value releases it if this side holds it.
+///
+/// Ringing also raises an interrupt for the other side, which the device cannot deliver itself: whoever steps that
+/// side takes it with [`MailboxEnd::take_interrupt`] and calls `Emulator::interrupt` with the doorbell port.
+///
+/// Arbitration is by access: each port access completes before the other side's next one, so taking the semaphore
+/// never interleaves with the other side. A scheduler stepping several cores must step them one instruction at a time
+/// in a fixed order, so that the lower-numbered side wins when both access the mailbox in the same step, and deliver a
+/// doorbell interrupt before the other side's next instruction.
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    registers: [u16; MAILBOX_REGISTERS as usize],
+    /// Value each side was last rung with and has not read yet.
+    doorbells: [Option<u16>; 2],
+    /// Whether each side has an interrupt not yet taken.
+    interrupts: [bool; 2],
+    /// Side holding the semaphore.
+    holder: Option<usize>,
+}
+
+impl Mailbox {
+    /// Create a mailbox with cleared registers, returning its two ends.
+    pub fn pair() -> (MailboxEnd, MailboxEnd) {
+        let shared = Rc::new(RefCell::new(Self::default()));
+        (
+            MailboxEnd {
+                mailbox: shared.clone(),
+                side: 0,
+                base: 0,
+            },
+            MailboxEnd {
+                mailbox: shared,
+                side: 1,
+                base: 0,
+            },
+        )
+    }
+}
+
+/// One side of a [`Mailbox`], as a device. Attach it with [`MailboxEnd::attach`], which tells it its base port; other
+/// ports it is reached through read as `0xFFFF` and ignore writes.
+#[derive(Debug, Clone)]
+pub struct MailboxEnd {
+    mailbox: Rc<RefCell<Mailbox>>,
+    side: usize,
+    base: u8,
+}
+
+impl MailboxEnd {
+    /// Attach this end to the [`MAILBOX_PORTS`] ports from `base`.
+    pub fn attach(mut self, ports: &mut Ports, base: u8) {
+        self.base = base;
+        for offset in 0..MAILBOX_PORTS {
+            ports.attach(base.wrapping_add(offset), Box::new(self.clone()));
+        }
+    }
+
+    /// Whether the other side has rung since the last call, clearing the interrupt.
+    pub fn take_interrupt(&self) -> bool {
+        std::mem::take(&mut self.mailbox.borrow_mut().interrupts[self.side])
+    }
+}
+
+impl PortDevice for MailboxEnd {
+    fn read(&mut self, port: u8) -> u16 {
+        let mut mailbox = self.mailbox.borrow_mut();
+        match port.wrapping_sub(self.base) {
+            MAILBOX_DOORBELL => mailbox.doorbells[self.side].take().unwrap_or(u16::MAX),
+            MAILBOX_SEMAPHORE => match mailbox.holder {
+                Some(holder) if holder != self.side => 1,
+                _ => {
+                    mailbox.holder = Some(self.side);
+                    0
+                }
+            },
+            register => mailbox
+                .registers
+                .get(register as usize)
+                .copied()
+                .unwrap_or(u16::MAX),
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u16) {
+        let mut mailbox = self.mailbox.borrow_mut();
+        let other = 1 - self.side;
+        match port.wrapping_sub(self.base) {
+            MAILBOX_DOORBELL => {
+                mailbox.doorbells[other] = Some(value);
+                mailbox.interrupts[other] = true;
+            }
+            MAILBOX_SEMAPHORE => {
+                if mailbox.holder == Some(self.side) {
+                    mailbox.holder = None;
+                }
+            }
+            register => {
+                if let Some(slot) = mailbox.registers.get_mut(register as usize) {
+                    *slot = value;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_device_given_to_every_port_sees_every_access() {
+        let shared = Rc::new(RefCell::new(ScriptedDevice::new([7])));
+        let mut ports = Ports::with_device(shared.clone());
+        ports.attach(3, Box::new(ScriptedDevice::new([])));
+        ports.write(0, 1);
+        ports.write(3, 2);
+        ports.write(0xFF, 3);
+        assert_eq!(ports.read(0x42), 7);
+        assert_eq!(shared.borrow().writes, [(0, 1), (0xFF, 3)]);
+    }
+
+    #[test]
+    fn a_scripted_device_repeats_its_last_read() {
+        let mut device = ScriptedDevice::new([0x00, 0x80]);
+        assert_eq!(device.read(1), 0x00);
+        assert_eq!(device.read(1), 0x80);
+        assert_eq!(device.read(1), 0x80);
+        device.push_read(0x01);
+        assert_eq!(device.read(1), 0x01);
+        assert_eq!(device.read(1), 0x01);
+        device.write(2, 0x1234);
+        assert_eq!(device.writes, [(2, 0x1234)]);
+        assert_eq!(ScriptedDevice::new([]).read(0), 0xFFFF);
+    }
+
+    #[test]
+    fn doorbells_ring_the_other_side_and_clear_on_read() {
+        let (first, second) = Mailbox::pair();
+        let (mut first_ports, mut second_ports) = (Ports::new(), Ports::new());
+        first.clone().attach(&mut first_ports, 0x10);
+        second.clone().attach(&mut second_ports, 0x20);
+        first_ports.write(0x10 + 2, 0xCAFE);
+        assert_eq!(second_ports.read(0x20 + 2), 0xCAFE);
+
+        first_ports.write(0x10 + MAILBOX_DOORBELL, 7);
+        assert!(!first.take_interrupt());
+        assert!(second.take_interrupt());
+        assert!(!second.take_interrupt());
+        assert_eq!(first_ports.read(0x10 + MAILBOX_DOORBELL), 0xFFFF);
+        assert_eq!(second_ports.read(0x20 + MAILBOX_DOORBELL), 7);
+        assert_eq!(second_ports.read(0x20 + MAILBOX_DOORBELL), 0xFFFF);
+        // Ports past the registers read as nothing.
+        assert_eq!(second_ports.read(0x20 + MAILBOX_PORTS), 0xFFFF);
+    }
+
+    #[test]
+    fn the_semaphore_is_held_by_one_side_at_a_time() {
+        let (mut first, mut second) = Mailbox::pair();
+        assert_eq!(first.read(MAILBOX_SEMAPHORE), 0);
+        assert_eq!(first.read(MAILBOX_SEMAPHORE), 0);
+        assert_eq!(second.read(MAILBOX_SEMAPHORE), 1);
+        // Only the holder can release it.
+        second.write(MAILBOX_SEMAPHORE, 0);
+        assert_eq!(second.read(MAILBOX_SEMAPHORE), 1);
+        first.write(MAILBOX_SEMAPHORE, 0);
+        assert_eq!(second.read(MAILBOX_SEMAPHORE), 0);
+        assert_eq!(first.read(MAILBOX_SEMAPHORE), 1);
+    }
+}